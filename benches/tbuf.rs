@@ -0,0 +1,142 @@
+// benches/tbuf.rs
+
+// Tbuf against the original Vec based implementation, which rescanned
+// the whole buffer on every add and expired with Vec::remove(0)
+
+use std::{
+    hint::black_box,
+    time::{Duration, SystemTime},
+};
+
+use coap_server_temp::tbuf::{AvgMode, Tbuf, Tdata};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+const WINDOWS: [u64; 2] = [900, 300];
+const SIZES: [usize; 3] = [1_000, 5_000, 20_000];
+
+struct OldTbuf {
+    averages_t: Vec<u64>,
+    averages: Vec<f64>,
+    buf: Vec<(SystemTime, f64)>,
+    buf_expire: u64,
+}
+
+impl OldTbuf {
+    fn new(averages_t: &[u64]) -> Self {
+        OldTbuf {
+            averages_t: averages_t.to_vec(),
+            averages: vec![f64::NAN; averages_t.len()],
+            buf: Vec::with_capacity(64),
+            buf_expire: *averages_t.iter().max().unwrap_or(&0),
+        }
+    }
+
+    fn add(&mut self, data: (SystemTime, f64)) {
+        self.buf.push(data);
+        self.update_averages();
+    }
+
+    fn expire(&mut self) -> usize {
+        let too_old = SystemTime::now()
+            .checked_sub(Duration::new(self.buf_expire, 0))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut n_expired = 0;
+        while self.buf.len() > 1 && self.buf[0].0 < too_old {
+            self.buf.remove(0);
+            n_expired += 1;
+        }
+        n_expired
+    }
+
+    fn update_averages(&mut self) {
+        let now = SystemTime::now();
+        for (i, t) in self.averages_t.iter().enumerate() {
+            let age_threshold = now
+                .checked_sub(Duration::new(*t, 0))
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let (size, sum) = self
+                .buf
+                .iter()
+                .filter(|(ts, _)| *ts > age_threshold)
+                .fold((0u64, 0.0), |(n, s), (_, d)| (n + 1, s + d));
+            self.averages[i] = if size > 0 {
+                sum / size as f64
+            } else {
+                f64::NAN
+            };
+        }
+    }
+}
+
+// n samples spread evenly over the span seconds before now
+fn samples(n: usize, span: u64) -> Vec<(SystemTime, f64)> {
+    let now = SystemTime::now();
+    let step = Duration::from_secs(span).as_secs_f64() / n as f64;
+    (0..n)
+        .map(|i| {
+            let age = Duration::from_secs_f64(step * (n - i) as f64);
+            (now - age, 20.0 + (i % 10) as f64 / 10.0)
+        })
+        .collect()
+}
+
+fn new_tbuf(samples: &[(SystemTime, f64)]) -> Tbuf {
+    let windows = WINDOWS.map(|t| (t, AvgMode::Arithmetic));
+    let mut tbuf = Tbuf::new(&windows, 600);
+    for (ts, d) in samples {
+        tbuf.add(Tdata::new((*d, *ts)));
+    }
+    tbuf
+}
+
+fn old_tbuf(samples: &[(SystemTime, f64)]) -> OldTbuf {
+    let mut tbuf = OldTbuf::new(&WINDOWS);
+    for s in samples {
+        tbuf.add(*s);
+    }
+    tbuf
+}
+
+// Fill an empty buffer with n samples, all inside the longest window
+fn bench_add(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add");
+    group.sample_size(10);
+    for n in SIZES {
+        let data = samples(n, 800);
+        group.bench_with_input(BenchmarkId::new("tbuf", n), &data, |b, data| {
+            b.iter(|| black_box(new_tbuf(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("old", n), &data, |b, data| {
+            b.iter(|| black_box(old_tbuf(data)))
+        });
+    }
+    group.finish();
+}
+
+// Expire the older half of a buffer of n samples
+fn bench_expire(c: &mut Criterion) {
+    let mut group = c.benchmark_group("expire");
+    group.sample_size(10);
+    for n in SIZES {
+        let data = samples(n, 1800);
+        group.bench_with_input(BenchmarkId::new("tbuf", n), &data, |b, data| {
+            b.iter_batched(
+                || new_tbuf(data),
+                |mut tbuf| black_box(tbuf.expire()),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("old", n), &data, |b, data| {
+            b.iter_batched(
+                || old_tbuf(data),
+                |mut tbuf| black_box(tbuf.expire()),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_add, bench_expire);
+criterion_main!(benches);
+// EOF
@@ -1,23 +1,32 @@
 // bin/coap_server_temp.rs
 
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     sync::{atomic, Arc},
     time,
 };
 
-use clap::Parser;
-use coap_lite::{CoapResponse, RequestType, ResponseType};
+use async_trait::async_trait;
+
+use clap::{Parser, ValueEnum};
+use coap_lite::{CoapOption, CoapResponse, ContentFormat, Packet, RequestType, ResponseType};
 use coap_server::{
-    app::{self, CoapError, Request, Response},
+    app::{self, CoapError, ObservableResource, Observers, Request, Response},
     CoapServer,
 };
 use coap_server_tokio::transport::udp::UdpTransport;
 use tracing::*;
 
 use coap_server_temp::*;
+use csvsink::CsvSink;
 use influxdb::InfluxSender;
-use sensordata::MyData;
+use linkformat::Link;
+use payload::{IngestStatus, SensorReadings};
+use sensordata::{MyData, TEMPERATURE};
+use sensormeta::SensorMeta;
+use sink::Sink;
+use tbuf::AvgMode;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -27,12 +36,33 @@ async fn main() -> anyhow::Result<()> {
     opts.start_pgm(env!("CARGO_BIN_NAME"));
 
     let srv_state = Arc::new(ServerState {
-        mydata: MyData::new(&opts),
+        mydata: MyData::new(&opts)?,
         counter: atomic::AtomicU64::new(0),
+        ingest_errors: atomic::AtomicU64::new(0),
+        admin_token: opts.admin_token.clone(),
+        db_counters: Default::default(),
     });
 
     tokio::spawn(run_expire(srv_state.clone(), opts.expire_interval));
-    tokio::spawn(InfluxSender::new(&opts, srv_state.clone()).run_db_send());
+    if let Some(listen) = &opts.metrics_listen {
+        metrics::start(listen, srv_state.clone()).await?;
+    }
+
+    let mut sinks: Vec<Arc<dyn Sink>> = Vec::new();
+    if !opts.no_influx {
+        sinks.push(Arc::new(InfluxSender::new(&opts, srv_state.clone())?));
+    }
+    if let Some(csv) = CsvSink::new(&opts)? {
+        sinks.push(Arc::new(csv));
+    }
+    for sink in sinks {
+        info!("Writing to {} every {} s", sink.name(), sink.interval());
+        tokio::spawn(sink::run_sink(
+            sink,
+            srv_state.clone(),
+            retry::RetryPolicy::new(&opts),
+        ));
+    }
 
     let addr = opts.listen.to_string();
 
@@ -43,10 +73,31 @@ async fn main() -> anyhow::Result<()> {
     Ok(server
         .serve(
             app::new()
-                .resource(app::resource("/avg_out").get({
+                .not_discoverable()
+                .block_transfer()
+                .resource(app::resource("/.well-known/core").get({
                     let state = srv_state.clone();
-                    move |req| resp_get_avg_out(req, state.clone())
+                    move |req| resp_get_well_known_core(req, state.clone())
                 }))
+                .resource(
+                    app::resource("/avg_out")
+                        .observable(AvgObserver::new(&opts, srv_state.clone(), false))
+                        .get({
+                            let state = srv_state.clone();
+                            move |req| resp_get_avg_out(req, state.clone())
+                        }),
+                )
+                .resource(
+                    app::resource("/calibration")
+                        .get({
+                            let state = srv_state.clone();
+                            move |req| resp_get_calibration(req, state.clone())
+                        })
+                        .post({
+                            let state = srv_state.clone();
+                            move |req| resp_post_calibration(req, state.clone())
+                        }),
+                )
                 .resource(app::resource("/dump").get({
                     let state = srv_state.clone();
                     move |req| resp_get_dump(req, state.clone())
@@ -55,9 +106,55 @@ async fn main() -> anyhow::Result<()> {
                     let state = srv_state.clone();
                     move |req| resp_get_list_sensors(req, state.clone())
                 }))
-                .resource(app::resource("/sensor").get({
+                .resource(app::resource("/list_quantities").get({
                     let state = srv_state.clone();
-                    move |req| resp_get_sensor(req, state.clone())
+                    move |req| resp_get_list_quantities(req, state.clone())
+                }))
+                .resource(
+                    app::resource("/meta")
+                        .get({
+                            let state = srv_state.clone();
+                            move |req| resp_get_meta(req, state.clone())
+                        })
+                        .put({
+                            let state = srv_state.clone();
+                            move |req| resp_put_meta(req, state.clone())
+                        })
+                        .delete({
+                            let state = srv_state.clone();
+                            move |req| resp_delete_meta(req, state.clone())
+                        }),
+                )
+                .resource(
+                    app::resource("/sensor")
+                        .observable(AvgObserver::new(&opts, srv_state.clone(), true))
+                        .get({
+                            let state = srv_state.clone();
+                            move |req| resp_get_sensor(req, state.clone())
+                        })
+                        .delete({
+                            let state = srv_state.clone();
+                            move |req| resp_delete_sensor(req, state.clone())
+                        }),
+                )
+                .resource(app::resource("/rename_sensor").post({
+                    let state = srv_state.clone();
+                    move |req| resp_post_rename_sensor(req, state.clone())
+                }))
+                .resource(
+                    app::resource("/senml")
+                        .get({
+                            let state = srv_state.clone();
+                            move |req| resp_get_senml(req, state.clone())
+                        })
+                        .post({
+                            let state = srv_state.clone();
+                            move |req| resp_post_senml(req, state.clone())
+                        }),
+                )
+                .resource(app::resource("/stats").get({
+                    let state = srv_state.clone();
+                    move |req| resp_get_stats(req, state.clone())
                 }))
                 .resource(app::resource("/set_outsensor").post({
                     let state = srv_state.clone();
@@ -88,6 +185,80 @@ pub async fn run_expire(mystate: Arc<ServerState>, interval: u64) {
     }
 }
 
+// Notifies the observers of /avg_out or /sensor/<id>[/<quantity>] when the
+// average over the out window has moved by more than delta,
+// and at least every max_age seconds
+struct AvgObserver {
+    mystate: Arc<ServerState>,
+    per_sensor: bool,
+    delta: f64,
+    max_age: time::Duration,
+}
+
+impl AvgObserver {
+    fn new(opts: &OptsCommon, mystate: Arc<ServerState>, per_sensor: bool) -> Self {
+        AvgObserver {
+            mystate,
+            per_sensor,
+            delta: opts.observe_delta,
+            max_age: time::Duration::from_secs(opts.observe_max_age),
+        }
+    }
+
+    async fn current(&self, path: &str) -> Option<f64> {
+        if !self.per_sensor {
+            return self.mystate.mydata.average_out().await;
+        }
+        let path = path.split('/').map(String::from).collect::<Vec<_>>();
+        let mydata = &self.mystate.mydata;
+        mydata
+            .average_get(
+                &path[0],
+                path_quantity(&path),
+                mydata.average_out_t().await,
+                mydata.average_out_mode().await,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl ObservableResource for AvgObserver {
+    async fn on_active(&self, observers: Observers) -> Observers {
+        let path = observers.relative_path().to_string();
+        let resource = if self.per_sensor { "sensor" } else { "avg_out" };
+        info!("Observers active on /{resource} {path}");
+
+        // subscribe first, so that no change after reading the value is missed
+        let mut changed = self.mystate.mydata.subscribe();
+        let mut last = self.current(&path).await;
+        let mut deadline = tokio::time::Instant::now() + self.max_age;
+        loop {
+            tokio::select! {
+                _ = observers.stay_active() => break,
+                Ok(()) = changed.changed() => {
+                    let value = self.current(&path).await;
+                    let moved = match (last, value) {
+                        (Some(a), Some(b)) => (a - b).abs() > self.delta,
+                        (None, None) => false,
+                        _ => true,
+                    };
+                    if !moved {
+                        continue;
+                    }
+                    last = value;
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    last = self.current(&path).await;
+                }
+            }
+            observers.notify_change().await;
+            deadline = tokio::time::Instant::now() + self.max_age;
+        }
+        observers
+    }
+}
+
 fn log_request(request: &Request<SocketAddr>, mystate: &mut Arc<ServerState>) {
     let id = mystate.counter.fetch_add(1, atomic::Ordering::Relaxed);
     let ip_str = match request.original.source {
@@ -99,17 +270,198 @@ fn log_request(request: &Request<SocketAddr>, mystate: &mut Arc<ServerState>) {
     info!("#{id} {ip_str} {method:?} /{path}");
 
     if let RequestType::Post = method {
-        let data = String::from_utf8_lossy(&request.original.message.payload);
+        let data = payload_text(&request.original.message);
         info!("<-- payload: {data}");
     }
 }
 
+// Parse the Uri-Query options of a request into key=value pairs
+fn get_query(request: &Request<SocketAddr>) -> HashMap<String, String> {
+    let mut query = HashMap::new();
+    if let Some(options) = request.original.message.get_option(CoapOption::UriQuery) {
+        for opt in options {
+            let opt = String::from_utf8_lossy(opt);
+            match opt.split_once('=') {
+                Some((k, v)) => query.insert(k.to_string(), v.to_string()),
+                None => query.insert(opt.to_string(), String::new()),
+            };
+        }
+    }
+    query
+}
+
+// Averaging window and mode from the "window" and "mode" query parameters,
+// None if invalid
+async fn query_window(
+    query: &HashMap<String, String>,
+    mystate: &ServerState,
+) -> Option<(u64, AvgMode)> {
+    let (t, mode) = match query.get("window") {
+        None => (
+            mystate.mydata.average_out_t().await,
+            mystate.mydata.average_out_mode().await,
+        ),
+        Some(w) => match w.parse::<u64>() {
+            Ok(w) if w > 0 && w <= mystate.mydata.retention().await => {
+                (w, mystate.mydata.average_mode())
+            }
+            _ => return None,
+        },
+    };
+    match query.get("mode") {
+        None => Some((t, mode)),
+        Some(m) => AvgMode::from_str(m, true).ok().map(|m| (t, m)),
+    }
+}
+
+// Quantity from a "<sensor_id>/<quantity>" path, temperature by default
+fn path_quantity(path: &[String]) -> &str {
+    path.get(1).map_or(TEMPERATURE, |q| q.as_str())
+}
+
+// Payload formats we speak
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Cbor,
+    SenmlJson,
+    SenmlCbor,
+}
+
+// Response format from the Accept option, text by default, None if unsupported
+fn accept_format(request: &Request<SocketAddr>) -> Option<Format> {
+    let Some(accept) = request
+        .original
+        .message
+        .get_option(CoapOption::Accept)
+        .and_then(|opts| opts.front())
+    else {
+        return Some(Format::Text);
+    };
+    match accept.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32) {
+        0 => Some(Format::Text),
+        50 => Some(Format::Json),
+        60 => Some(Format::Cbor),
+        110 => Some(Format::SenmlJson),
+        112 => Some(Format::SenmlCbor),
+        _ => None,
+    }
+}
+
+fn set_not_acceptable(resp: &mut Response) {
+    resp.set_status(ResponseType::NotAcceptable);
+    resp.message.payload = "NOT ACCEPTABLE".into();
+}
+
+// Set the payload in the given format, text is the plain text version of value
+fn set_payload<T, F>(resp: &mut Response, format: Format, value: &T, text: F)
+where
+    T: serde::Serialize,
+    F: FnOnce() -> String,
+{
+    let encoded = match format {
+        Format::Text => {
+            resp.message.payload = text().into();
+            return;
+        }
+        Format::Json => serde_json::to_vec(value)
+            .map(|payload| (ContentFormat::ApplicationJSON, payload))
+            .map_err(|e| e.to_string()),
+        Format::Cbor => {
+            let mut payload = Vec::new();
+            ciborium::into_writer(value, &mut payload)
+                .map(|_| (ContentFormat::ApplicationCBOR, payload))
+                .map_err(|e| e.to_string())
+        }
+        // SenML needs records, see set_senml_payload()
+        Format::SenmlJson | Format::SenmlCbor => {
+            set_not_acceptable(resp);
+            return;
+        }
+    };
+    set_encoded_payload(resp, encoded);
+}
+
+// Set a SenML pack payload, JSON unless CBOR was asked for
+fn set_senml_payload(resp: &mut Response, format: Format, pack: &[senml::Record]) {
+    let encoded = match format {
+        Format::SenmlCbor => {
+            senml::to_cbor(pack).map(|payload| (ContentFormat::ApplicationSenmlCBOR, payload))
+        }
+        _ => serde_json::to_vec(pack)
+            .map(|payload| (ContentFormat::ApplicationSenmlJSON, payload))
+            .map_err(|e| e.to_string()),
+    };
+    set_encoded_payload(resp, encoded);
+}
+
+fn set_encoded_payload(resp: &mut Response, encoded: Result<(ContentFormat, Vec<u8>), String>) {
+    match encoded {
+        Ok((content_format, payload)) => {
+            resp.message.set_content_format(content_format);
+            resp.message.payload = payload;
+        }
+        Err(e) => {
+            error!("Cannot serialize response: {e}");
+            resp.set_status(ResponseType::InternalServerError);
+            resp.message.payload = "INTERNAL ERROR".into();
+        }
+    }
+}
+
+// Set the payload of a single reading, as SenML if asked for
+fn set_reading_payload(
+    resp: &mut Response,
+    format: Format,
+    sensor_id: &str,
+    quantity: &str,
+    value: f64,
+) {
+    match format {
+        Format::SenmlJson | Format::SenmlCbor => set_senml_payload(
+            resp,
+            format,
+            &senml::pack(&[(sensor_id.into(), quantity.into(), value)]),
+        ),
+        _ => set_payload(resp, format, &value, || format!("{value:.2}")),
+    }
+}
+
+// Check the "token" query parameter against --admin_token,
+// setting the error response if it does not match
+fn authorized(query: &HashMap<String, String>, mystate: &ServerState, resp: &mut Response) -> bool {
+    match (&mystate.admin_token, query.get("token")) {
+        (Some(admin_token), Some(token)) if token == admin_token => true,
+        (None, _) => {
+            resp.set_status(ResponseType::Forbidden);
+            resp.message.payload = "NO ADMIN TOKEN SET".into();
+            false
+        }
+        _ => {
+            resp.set_status(ResponseType::Unauthorized);
+            resp.message.payload = "UNAUTHORIZED".into();
+            false
+        }
+    }
+}
+
 fn log_response(response: &CoapResponse) {
     let code = response.message.header.code.to_string();
-    let data = String::from_utf8_lossy(&response.message.payload);
+    let data = payload_text(&response.message);
     info!("--> {code:?} {data}");
 }
 
+// Payload for the log, binary formats only by their size
+fn payload_text(message: &Packet) -> String {
+    match message.get_content_format() {
+        Some(ContentFormat::ApplicationCBOR | ContentFormat::ApplicationSenmlCBOR) => {
+            format!("<{} bytes of CBOR>", message.payload.len())
+        }
+        _ => String::from_utf8_lossy(&message.payload).into_owned(),
+    }
+}
+
 async fn resp_get_avg_out(
     request: Request<SocketAddr>,
     mut mystate: Arc<ServerState>,
@@ -117,14 +469,18 @@ async fn resp_get_avg_out(
     log_request(&request, &mut mystate);
 
     let mut resp = request.new_response();
-    match mystate.mydata.average_out().await {
-        None => {
+    match (
+        accept_format(&request),
+        mystate.mydata.average_out_sensor().await,
+    ) {
+        (None, _) => set_not_acceptable(&mut resp),
+        (_, None) => {
             resp.set_status(ResponseType::ServiceUnavailable);
             resp.message.payload = "NO DATA".into();
         }
-        Some(avg) => {
+        (Some(format), Some((sensor_id, avg))) => {
             resp.set_status(ResponseType::Content);
-            resp.message.payload = format!("{avg:.2}").into();
+            set_reading_payload(&mut resp, format, &sensor_id, TEMPERATURE, avg);
         }
     }
 
@@ -132,16 +488,105 @@ async fn resp_get_avg_out(
     Ok(resp)
 }
 
-async fn resp_get_dump(
+async fn resp_get_calibration(
     request: Request<SocketAddr>,
     mut mystate: Arc<ServerState>,
 ) -> Result<Response, CoapError> {
     log_request(&request, &mut mystate);
 
     let mut resp = request.new_response();
-    mystate.mydata.dump().await;
     resp.set_status(ResponseType::Content);
-    resp.message.payload = "SEE SERVER LOG".into();
+    resp.message.payload = mystate
+        .mydata
+        .calibrations()
+        .await
+        .iter()
+        .map(|(sensor_id, cal)| format!("{sensor_id} {cal}"))
+        .collect::<Vec<String>>()
+        .join("\n")
+        .into();
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+// Payload "<sensor_id> <scale> <offset>" sets a calibration,
+// plain "<sensor_id>" removes it.
+async fn resp_post_calibration(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let mut resp = request.new_response();
+    let req_payload = String::from_utf8_lossy(&request.original.message.payload);
+    let req_payload = req_payload.trim();
+
+    let save_failed = |e: anyhow::Error| {
+        error!("Cannot save calibrations: {e}");
+        (ResponseType::InternalServerError, "SAVE FAILED")
+    };
+    let res = match req_payload.split_once(char::is_whitespace) {
+        _ if req_payload.is_empty() => Err((ResponseType::BadRequest, "NO DATA")),
+        None => match mystate.mydata.remove_calibration(req_payload).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err((ResponseType::NotFound, "NOT FOUND")),
+            Err(e) => Err(save_failed(e)),
+        },
+        Some((sensor_id, cal)) => match cal.parse() {
+            Err(_) => Err((ResponseType::BadRequest, "INVALID DATA")),
+            Ok(cal) => mystate
+                .mydata
+                .set_calibration(sensor_id, cal)
+                .await
+                .map_err(save_failed),
+        },
+    };
+
+    resp.message.payload = match res {
+        Ok(()) => {
+            resp.set_status(ResponseType::Content);
+            "OK".into()
+        }
+        Err((status, msg)) => {
+            resp.set_status(status);
+            msg.into()
+        }
+    };
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_get_dump(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    // large dumps are sent with Block2
+    let mut resp = request.new_response();
+    match accept_format(&request) {
+        Some(Format::Text | Format::Json) => {
+            resp.set_status(ResponseType::Content);
+            set_payload(
+                &mut resp,
+                Format::Json,
+                &mystate.mydata.dump().await,
+                String::new,
+            );
+        }
+        Some(Format::Cbor) => {
+            resp.set_status(ResponseType::Content);
+            set_payload(
+                &mut resp,
+                Format::Cbor,
+                &mystate.mydata.dump().await,
+                String::new,
+            );
+        }
+        _ => set_not_acceptable(&mut resp),
+    }
 
     log_response(&resp);
     Ok(resp)
@@ -154,14 +599,107 @@ async fn resp_get_list_sensors(
     log_request(&request, &mut mystate);
 
     let mut resp = request.new_response();
-    resp.set_status(ResponseType::Content);
-    resp.message.payload = mystate.mydata.sensors_list().await.join(" ").into();
+    match accept_format(&request) {
+        None => set_not_acceptable(&mut resp),
+        Some(format) => {
+            let sensors = mystate.mydata.sensors_list().await;
+            resp.set_status(ResponseType::Content);
+            set_payload(&mut resp, format, &sensors, || sensors.join(" "));
+        }
+    }
 
     log_response(&resp);
     Ok(resp)
 }
 
-async fn resp_get_sensor(
+async fn resp_get_list_quantities(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let path = &request.unmatched_path;
+    let mut resp = request.new_response();
+    resp.set_status(ResponseType::NotFound);
+    resp.message.payload = "NOT FOUND".into();
+
+    if !path.is_empty()
+        && let Some(quantities) = mystate.mydata.quantities_list(&path[0]).await
+    {
+        resp.set_status(ResponseType::Content);
+        resp.message.payload = quantities.join(" ").into();
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+// GET /meta/<sensor_id> returns the metadata of one sensor, GET /meta all of them
+async fn resp_get_meta(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let path = &request.unmatched_path;
+    let mut resp = request.new_response();
+    resp.set_status(ResponseType::NotFound);
+    resp.message.payload = "NOT FOUND".into();
+
+    if path.is_empty() {
+        resp.set_status(ResponseType::Content);
+        set_payload(
+            &mut resp,
+            Format::Json,
+            &mystate.mydata.metas().await,
+            String::new,
+        );
+    } else if let Some(meta) = mystate.mydata.meta_get(&path[0]).await {
+        resp.set_status(ResponseType::Content);
+        set_payload(&mut resp, Format::Json, &meta, String::new);
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_put_meta(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let path = &request.unmatched_path;
+    let mut resp = request.new_response();
+
+    resp.message.payload = if path.is_empty() {
+        resp.set_status(ResponseType::BadRequest);
+        "NO SENSOR ID".into()
+    } else {
+        match serde_json::from_slice::<SensorMeta>(&request.original.message.payload) {
+            Err(_) => {
+                resp.set_status(ResponseType::BadRequest);
+                "INVALID DATA".into()
+            }
+            Ok(meta) => match mystate.mydata.set_meta(&path[0], meta).await {
+                Ok(()) => {
+                    resp.set_status(ResponseType::Changed);
+                    "OK".into()
+                }
+                Err(e) => {
+                    error!("Cannot save metadata: {e}");
+                    resp.set_status(ResponseType::InternalServerError);
+                    "SAVE FAILED".into()
+                }
+            },
+        }
+    };
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_delete_meta(
     request: Request<SocketAddr>,
     mut mystate: Arc<ServerState>,
 ) -> Result<Response, CoapError> {
@@ -173,11 +711,283 @@ async fn resp_get_sensor(
     resp.message.payload = "NOT FOUND".into();
 
     if !path.is_empty() {
-        let t = mystate.mydata.average_out_t().await;
-        if let Some(d) = mystate.mydata.average_get(&path[0], t).await {
+        match mystate.mydata.remove_meta(&path[0]).await {
+            Ok(false) => {}
+            Ok(true) => {
+                resp.set_status(ResponseType::Deleted);
+                resp.message.payload = "OK".into();
+            }
+            Err(e) => {
+                error!("Cannot save metadata: {e}");
+                resp.set_status(ResponseType::InternalServerError);
+                resp.message.payload = "SAVE FAILED".into();
+            }
+        }
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_delete_sensor(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let path = &request.unmatched_path;
+    let query = get_query(&request);
+    let mut resp = request.new_response();
+
+    // sensor ids cannot contain "/", so the id is always one path segment
+    if authorized(&query, &mystate, &mut resp) {
+        resp.message.payload = match path.as_slice() {
+            [sensor_id] if payload::valid_name(sensor_id) => {
+                if mystate.mydata.remove_sensor(sensor_id).await {
+                    resp.set_status(ResponseType::Deleted);
+                    "OK".into()
+                } else {
+                    resp.set_status(ResponseType::NotFound);
+                    "NOT FOUND".into()
+                }
+            }
+            _ => {
+                resp.set_status(ResponseType::BadRequest);
+                "INVALID SENSOR ID".into()
+            }
+        };
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_get_sensor(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let path = &request.unmatched_path;
+    let query = get_query(&request);
+    let mut resp = request.new_response();
+    resp.set_status(ResponseType::NotFound);
+    resp.message.payload = "NOT FOUND".into();
+
+    let Some(format) = accept_format(&request) else {
+        set_not_acceptable(&mut resp);
+        log_response(&resp);
+        return Ok(resp);
+    };
+
+    // /sensor/<id>[/<quantity>]/history
+    if path.len() > 1 && path[path.len() - 1] == "history" {
+        set_history_payload(&mut resp, format, &query, &mystate, &path[..path.len() - 1]).await;
+        log_response(&resp);
+        return Ok(resp);
+    }
+
+    if !path.is_empty()
+        && mystate
+            .mydata
+            .is_stale(&path[0], path_quantity(path))
+            .await
+            .unwrap_or(false)
+    {
+        resp.set_status(ResponseType::ServiceUnavailable);
+        resp.message.payload = "STALE".into();
+        log_response(&resp);
+        return Ok(resp);
+    }
+
+    match query_window(&query, &mystate).await {
+        None => {
+            resp.set_status(ResponseType::BadRequest);
+            resp.message.payload = "INVALID WINDOW".into();
+        }
+        Some((t, mode)) => {
+            if !path.is_empty()
+                && let Some(d) = mystate
+                    .mydata
+                    .average_get(&path[0], path_quantity(path), t, mode)
+                    .await
+            {
+                resp.set_status(ResponseType::Content);
+                set_reading_payload(&mut resp, format, &path[0], path_quantity(path), d);
+            }
+        }
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+// Buffered samples of a sensor, filtered by the "since" and "limit" query parameters
+async fn set_history_payload(
+    resp: &mut Response,
+    format: Format,
+    query: &HashMap<String, String>,
+    mystate: &ServerState,
+    path: &[String],
+) {
+    let since = query.get("since").map(|s| s.parse::<f64>());
+    let limit = query.get("limit").map(|s| s.parse::<usize>());
+    let (Ok(since), Ok(limit)) = (since.transpose(), limit.transpose()) else {
+        resp.set_status(ResponseType::BadRequest);
+        resp.message.payload = "INVALID QUERY".into();
+        return;
+    };
+
+    if let Some(samples) = mystate
+        .mydata
+        .history(&path[0], path_quantity(path), since, limit)
+        .await
+    {
+        resp.set_status(ResponseType::Content);
+        set_payload(resp, format, &samples, || {
+            samples
+                .iter()
+                .map(|s| format!("{:.3} {:.2} {:.2}", s.ts, s.value, s.raw))
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+    }
+}
+
+async fn resp_get_senml(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let query = get_query(&request);
+    let mut resp = request.new_response();
+
+    match (
+        accept_format(&request),
+        query_window(&query, &mystate).await,
+    ) {
+        (Some(Format::Text | Format::SenmlJson), Some((t, mode))) => {
+            let averages = mystate.mydata.averages_get(t, mode).await;
+            resp.set_status(ResponseType::Content);
+            set_senml_payload(&mut resp, Format::SenmlJson, &senml::pack(&averages));
+        }
+        (Some(Format::SenmlCbor), Some((t, mode))) => {
+            let averages = mystate.mydata.averages_get(t, mode).await;
             resp.set_status(ResponseType::Content);
-            resp.message.payload = format!("{d:.2}").into();
+            set_senml_payload(&mut resp, Format::SenmlCbor, &senml::pack(&averages));
+        }
+        (Some(_), None) => {
+            resp.set_status(ResponseType::BadRequest);
+            resp.message.payload = "INVALID WINDOW".into();
         }
+        _ => set_not_acceptable(&mut resp),
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_post_senml(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let mut resp = request.new_response();
+
+    let req_payload = &request.original.message.payload;
+    let (format, parsed) = match request.original.message.get_content_format() {
+        Some(ContentFormat::ApplicationSenmlCBOR | ContentFormat::ApplicationCBOR) => {
+            (Format::Cbor, senml::parse_cbor(req_payload))
+        }
+        _ => (Format::Json, senml::parse_json(req_payload)),
+    };
+    match parsed.and_then(senml::readings) {
+        Ok(readings) => {
+            let status = store_readings(&mystate, &readings).await;
+            resp.set_status(ingest_status(&status));
+            set_payload(&mut resp, format, &status, String::new);
+        }
+        Err(e) => {
+            ingest_errors(&mystate, 1);
+            resp.set_status(ResponseType::BadRequest);
+            set_payload(&mut resp, format, &e, String::new);
+        }
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_get_stats(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let path = &request.unmatched_path;
+    let query = get_query(&request);
+    let mut resp = request.new_response();
+    resp.set_status(ResponseType::NotFound);
+    resp.message.payload = "NOT FOUND".into();
+
+    match query_window(&query, &mystate).await {
+        None => {
+            resp.set_status(ResponseType::BadRequest);
+            resp.message.payload = "INVALID WINDOW".into();
+        }
+        Some((t, mode)) => {
+            if !path.is_empty()
+                && let Some(st) = mystate
+                    .mydata
+                    .stats_get(&path[0], path_quantity(path), t, mode)
+                    .await
+            {
+                let stale = mystate
+                    .mydata
+                    .is_stale(&path[0], path_quantity(path))
+                    .await
+                    .unwrap_or(false);
+                resp.set_status(ResponseType::Content);
+                resp.message.payload = format!(
+                    "count={} mean={:.2} min={:.2} max={:.2} stddev={:.2} first={:.2} last={:.2} ewma={:.2} stale={stale}",
+                    st.count, st.mean, st.min, st.max, st.stddev, st.first, st.last, st.ewma
+                )
+                .into();
+            }
+        }
+    }
+
+    log_response(&resp);
+    Ok(resp)
+}
+
+async fn resp_post_rename_sensor(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let query = get_query(&request);
+    let mut resp = request.new_response();
+
+    if authorized(&query, &mystate, &mut resp) {
+        let req_payload = String::from_utf8_lossy(&request.original.message.payload);
+        let indata = req_payload.split_whitespace().collect::<Vec<&str>>();
+        resp.message.payload = if indata.len() != 2 {
+            resp.set_status(ResponseType::BadRequest);
+            "INVALID DATA".into()
+        } else if !payload::valid_name(indata[1]) {
+            resp.set_status(ResponseType::BadRequest);
+            "INVALID SENSOR ID".into()
+        } else if mystate.mydata.rename_sensor(indata[0], indata[1]).await {
+            resp.set_status(ResponseType::Content);
+            "OK".into()
+        } else {
+            resp.set_status(ResponseType::NotFound);
+            "NOT FOUND".into()
+        };
     }
 
     log_response(&resp);
@@ -212,38 +1022,149 @@ async fn resp_post_store_temp(
     log_request(&request, &mut mystate);
 
     let mut resp = request.new_response();
-    let req_payload = String::from_utf8_lossy(&request.original.message.payload);
 
-    resp.message.payload = if req_payload.is_empty() {
+    let req_payload = &request.original.message.payload;
+    let parsed = match request.original.message.get_content_format() {
+        Some(ContentFormat::ApplicationJSON) => {
+            Some((Format::Json, payload::parse_json(req_payload)))
+        }
+        Some(ContentFormat::ApplicationCBOR) => {
+            Some((Format::Cbor, payload::parse_cbor(req_payload)))
+        }
+        _ => None,
+    };
+    if let Some((format, parsed)) = parsed {
+        match parsed {
+            Ok(readings) => {
+                let status = store_readings(&mystate, &readings).await;
+                resp.set_status(ingest_status(&status));
+                set_payload(&mut resp, format, &status, || "OK".into());
+            }
+            Err(e) => {
+                ingest_errors(&mystate, 1);
+                resp.set_status(ResponseType::BadRequest);
+                set_payload(&mut resp, format, &e, || e.error.clone());
+            }
+        }
+        log_response(&resp);
+        return Ok(resp);
+    }
+
+    // One reading per line, answered with one result line each
+    let lines = payload::parse_lines(&String::from_utf8_lossy(&request.original.message.payload));
+    resp.message.payload = if lines.is_empty() {
+        ingest_errors(&mystate, 1);
         resp.set_status(ResponseType::BadRequest);
         "NO DATA".into()
     } else {
-        let indata = req_payload.split_whitespace().collect::<Vec<&str>>();
-        if indata.len() == 2 {
-            match indata[1].parse::<f32>() {
-                Ok(temp) => {
-                    let name = indata[0].to_string();
-                    tokio::spawn(async move {
-                        mystate.mydata.add(name, temp).await;
-                    });
-                    resp.set_status(ResponseType::Content);
-                    "OK".into()
-                }
-                Err(_) => {
-                    resp.set_status(ResponseType::BadRequest);
-                    "INVALID NUMBER".into()
-                }
-            }
+        let readings = lines
+            .iter()
+            .filter_map(|line| line.as_ref().ok().cloned())
+            .collect::<Vec<_>>();
+        let mut stored = mystate.mydata.add_readings(&readings).await.into_iter();
+
+        let results = lines
+            .iter()
+            .map(|line| match line {
+                Err(msg) => msg.to_string(),
+                Ok(_) => match stored.next() {
+                    Some(Err(e)) => format!("REJECTED: {e}"),
+                    _ => "OK".to_string(),
+                },
+            })
+            .collect::<Vec<_>>();
+        ingest_errors(&mystate, results.iter().filter(|r| *r != "OK").count());
+        resp.set_status(if results.iter().any(|r| r == "OK") {
+            ResponseType::Content
         } else {
-            resp.set_status(ResponseType::BadRequest);
-            "INVALID DATA".into()
-        }
+            ResponseType::BadRequest
+        });
+        results.join("\n").into()
     };
 
     log_response(&resp);
     Ok(resp)
 }
 
+// Store the readings, telling which of them were rejected and why
+async fn store_readings(mystate: &ServerState, readings: &[SensorReadings]) -> IngestStatus {
+    let results = mystate.mydata.add_readings(readings).await;
+    let status = IngestStatus::new(readings, &results);
+    ingest_errors(mystate, status.rejected.len());
+    status
+}
+
+// 4.00 if nothing could be stored
+fn ingest_status(status: &IngestStatus) -> ResponseType {
+    if status.is_ok() {
+        ResponseType::Content
+    } else {
+        ResponseType::BadRequest
+    }
+}
+
+fn ingest_errors(mystate: &ServerState, n: usize) {
+    mystate
+        .ingest_errors
+        .fetch_add(n as u64, atomic::Ordering::Relaxed);
+}
+
+async fn resp_get_well_known_core(
+    request: Request<SocketAddr>,
+    mut mystate: Arc<ServerState>,
+) -> Result<Response, CoapError> {
+    log_request(&request, &mut mystate);
+
+    let query = get_query(&request);
+    let mut resp = request.new_response();
+
+    let mut links = vec![
+        Link::new("/avg_out")
+            .attr("rt", TEMPERATURE)
+            .attr("if", "sensor")
+            .flag("obs"),
+        Link::new("/calibration"),
+        Link::new("/dump"),
+        Link::new("/list_quantities"),
+        Link::new("/list_sensors"),
+        Link::new("/meta"),
+        Link::new("/rename_sensor"),
+        Link::new("/senml").attr("ct", "110 112"),
+        Link::new("/set_outsensor"),
+        Link::new("/stats"),
+        Link::new("/store_temp"),
+    ];
+    for sensor_id in mystate.mydata.sensors_list().await {
+        let quantities = mystate
+            .mydata
+            .quantities_list(&sensor_id)
+            .await
+            .unwrap_or_default();
+        for quantity in quantities {
+            let href = if quantity == TEMPERATURE {
+                format!("/sensor/{sensor_id}")
+            } else {
+                format!("/sensor/{sensor_id}/{quantity}")
+            };
+            links.push(
+                Link::new(href)
+                    .attr("rt", quantity)
+                    .attr("if", "sensor")
+                    .flag("obs"),
+            );
+        }
+    }
+    links.retain(|link| query.iter().all(|(k, v)| link.matches(k, v)));
+
+    resp.set_status(ResponseType::Content);
+    resp.message
+        .set_content_format(ContentFormat::ApplicationLinkFormat);
+    resp.message.payload = linkformat::format(&links).into();
+
+    log_response(&resp);
+    Ok(resp)
+}
+
 async fn resp_default(
     request: Request<SocketAddr>,
     mut mystate: Arc<ServerState>,
@@ -0,0 +1,162 @@
+// calibration.rs
+
+use std::{collections::HashMap, fmt, fs, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use tracing::*;
+
+use super::config;
+
+// Calibrated value is raw * scale + offset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.scale, self.offset)
+    }
+}
+
+// Parse "<scale> <offset>"
+impl FromStr for Calibration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields = s.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 2 {
+            bail!("expected <scale> <offset>, got \"{s}\"");
+        }
+        let cal = Calibration {
+            scale: fields[0].parse()?,
+            offset: fields[1].parse()?,
+        };
+        // NaN or inf would poison every reading of the sensor
+        if !cal.scale.is_finite() || !cal.offset.is_finite() {
+            bail!("scale and offset must be finite numbers, got \"{s}\"");
+        }
+        Ok(cal)
+    }
+}
+
+// Per-sensor calibrations, optionally backed by a file with
+// one "<sensor_id> <scale> <offset>" line per sensor.
+#[derive(Debug, Default)]
+pub struct CalibrationTable {
+    file: Option<String>,
+    table: HashMap<String, Calibration>,
+}
+
+impl CalibrationTable {
+    pub fn new(opts: &config::OptsCommon) -> anyhow::Result<Self> {
+        let mut cal = CalibrationTable {
+            file: opts.calibration_file.clone(),
+            table: HashMap::new(),
+        };
+        if let Some(file) = &opts.calibration_file {
+            match fs::read_to_string(file) {
+                Ok(data) => cal.parse(&data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    info!("Calibration file {file} not found, starting empty");
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(cal)
+    }
+
+    fn parse(&mut self, data: &str) -> anyhow::Result<()> {
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (sensor_id, cal) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("calibration line {}: no scale and offset", i + 1))?;
+            let cal = cal
+                .parse::<Calibration>()
+                .map_err(|e| anyhow!("calibration line {}: {e}", i + 1))?;
+            self.table.insert(sensor_id.to_string(), cal);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(file) = &self.file {
+            let mut data = String::new();
+            for (sensor_id, cal) in self.list() {
+                data.push_str(&format!("{sensor_id} {cal}\n"));
+            }
+            fs::write(file, data)?;
+        }
+        Ok(())
+    }
+
+    pub fn get<S: AsRef<str>>(&self, sensor_id: S) -> Calibration {
+        self.table
+            .get(sensor_id.as_ref())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Sorted list of all calibrations
+    pub fn list(&self) -> Vec<(String, Calibration)> {
+        let mut list = self
+            .table
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    }
+
+    pub fn set<S: AsRef<str>>(&mut self, sensor_id: S, cal: Calibration) -> anyhow::Result<()> {
+        self.table.insert(sensor_id.as_ref().into(), cal);
+        self.save()
+    }
+
+    pub fn remove<S: AsRef<str>>(&mut self, sensor_id: S) -> anyhow::Result<bool> {
+        let found = self.table.remove(sensor_id.as_ref()).is_some();
+        self.save()?;
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "1.02 -0.5".parse::<Calibration>().unwrap(),
+            Calibration {
+                scale: 1.02,
+                offset: -0.5
+            }
+        );
+        for s in ["1.0", "1.0 0 0", "x 0", "NaN 0", "1.0 inf", "-inf 0"] {
+            assert!(s.parse::<Calibration>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn non_finite_in_file() {
+        let mut cal = CalibrationTable::default();
+        let e = cal.parse("abc 1.0 0\ndef nan 0\n").unwrap_err();
+        assert!(e.to_string().starts_with("calibration line 2:"), "{e}");
+    }
+}
+// EOF
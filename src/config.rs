@@ -6,6 +6,9 @@ pub use std::ffi::OsString;
 use clap::Parser;
 use tracing::*;
 
+use crate::influxdb::DbApi;
+use crate::tbuf::AvgMode;
+
 #[derive(Clone, Debug, Default, Parser)]
 pub struct OptsCommon {
     #[arg(short, long)]
@@ -20,8 +23,20 @@ pub struct OptsCommon {
     pub average_db_t: u64,
     #[arg(long, default_value_t = 900)]
     pub average_out_t: u64,
+    #[arg(long, value_enum, default_value_t = AvgMode::Arithmetic)]
+    pub average_mode: AvgMode,
+    #[arg(long, value_enum)]
+    pub average_db_mode: Option<AvgMode>,
+    #[arg(long, value_enum)]
+    pub average_out_mode: Option<AvgMode>,
+    #[arg(long, default_value_t = 600)]
+    pub ewma_half_life: u64,
     #[arg(long, default_value_t = 300)]
     pub send_interval: i64,
+    #[arg(long)]
+    pub no_influx: bool,
+    #[arg(long, value_enum, default_value_t = DbApi::V2)]
+    pub db_api: DbApi,
     #[arg(long, default_value = "http://127.0.0.1:8086")]
     pub db_url: String,
     #[arg(long, default_value = "secret_token")]
@@ -31,13 +46,81 @@ pub struct OptsCommon {
     #[arg(long, default_value = "temperature")]
     pub bucket: String,
     #[arg(long, default_value = "temperature")]
+    pub database: String,
+    #[arg(long)]
+    pub db_rp: Option<String>,
+    #[arg(long)]
+    pub db_user: Option<String>,
+    #[arg(long)]
+    pub db_password: Option<String>,
+    #[arg(long, default_value = "temperature")]
     pub measurement: String,
+    #[arg(long)]
+    pub db_ewma: bool,
+    #[arg(long, default_value_t = 30)]
+    pub db_timeout: u64,
+    #[arg(long, default_value_t = 5)]
+    pub db_retry_max: u32,
+    #[arg(long, default_value_t = 500)]
+    pub db_retry_base_ms: u64,
+    #[arg(long, default_value_t = 30_000)]
+    pub db_retry_max_ms: u64,
+    #[arg(long)]
+    pub spool_dir: Option<String>,
+    #[arg(long, default_value_t = 10_485_760)]
+    pub spool_max_bytes: u64,
+    #[arg(long, default_value_t = 604_800)]
+    pub spool_max_age: u64,
+    #[arg(long)]
+    pub csv_dir: Option<String>,
+    #[arg(long, default_value_t = 300)]
+    pub csv_interval: i64,
     #[arg(long, default_value_t = 30)]
     pub expire_interval: u64,
+    #[arg(long, default_value_t = 0)]
+    pub stale_after: u64,
+    #[arg(long, default_value_t = 0)]
+    pub remove_after: u64,
+    #[arg(long)]
+    pub calibration_file: Option<String>,
+    #[arg(long)]
+    pub meta_file: Option<String>,
+    #[arg(long)]
+    pub admin_token: Option<String>,
+    #[arg(long)]
+    pub metrics_listen: Option<String>,
+    #[arg(long, allow_hyphen_values = true)]
+    pub filter_min: Option<f64>,
+    #[arg(long, allow_hyphen_values = true)]
+    pub filter_max: Option<f64>,
+    #[arg(long)]
+    pub filter_max_rate: Option<f64>,
+    #[arg(long, default_value_t = 0)]
+    pub filter_window: usize,
+    #[arg(long, default_value_t = 3.0)]
+    pub filter_hampel_k: f64,
+    #[arg(long, default_value_t = 0.5)]
+    pub filter_min_dev: f64,
+    #[arg(long, default_value_t = 60)]
+    pub ts_max_future: u64,
+    #[arg(long)]
+    pub ts_max_past: Option<u64>,
+    #[arg(long, default_value_t = 0.1)]
+    pub observe_delta: f64,
+    #[arg(long, default_value_t = 300)]
+    pub observe_max_age: u64,
 }
 
 impl OptsCommon {
     pub fn finalize(&mut self) -> anyhow::Result<()> {
+        // a sensor must be flagged stale before it is removed
+        if self.stale_after > 0 && self.remove_after > 0 && self.remove_after < self.stale_after {
+            anyhow::bail!(
+                "remove_after ({}) must not be shorter than stale_after ({})",
+                self.remove_after,
+                self.stale_after
+            );
+        }
         Ok(())
     }
 
@@ -64,4 +147,33 @@ impl OptsCommon {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_after_not_before_stale() {
+        let finalize = |args: &[&str]| {
+            OptsCommon::parse_from(["test"].iter().chain(args))
+                .finalize()
+                .is_ok()
+        };
+        assert!(finalize(&[
+            "--stale-after",
+            "600",
+            "--remove-after",
+            "3600"
+        ]));
+        assert!(finalize(&["--stale-after", "600", "--remove-after", "600"]));
+        assert!(finalize(&["--stale-after", "0", "--remove-after", "300"]));
+        assert!(finalize(&["--stale-after", "600", "--remove-after", "0"]));
+        assert!(!finalize(&[
+            "--stale-after",
+            "600",
+            "--remove-after",
+            "300"
+        ]));
+    }
+}
+
 // EOF
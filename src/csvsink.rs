@@ -0,0 +1,111 @@
+// csvsink.rs
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::*;
+use tracing::*;
+
+use super::config;
+use super::sink::{Aggregate, Sink};
+
+const HEADER: &str = "timestamp,sensor,quantity,count,mean,min,max,stddev,ewma\n";
+
+// Local archive of the aggregates, one CSV file per day (UTC)
+#[derive(Clone, Debug)]
+pub struct CsvSink {
+    dir: PathBuf,
+    interval: i64,
+}
+
+impl CsvSink {
+    pub fn new(opts: &config::OptsCommon) -> anyhow::Result<Option<Self>> {
+        let Some(dir) = &opts.csv_dir else {
+            return Ok(None);
+        };
+        fs::create_dir_all(dir)?;
+        Ok(Some(CsvSink {
+            dir: dir.into(),
+            interval: opts.csv_interval,
+        }))
+    }
+
+    fn path(&self, timestamp: i64) -> PathBuf {
+        let day = DateTime::from_timestamp(timestamp, 0)
+            .unwrap_or_default()
+            .format("%Y-%m-%d");
+        self.dir.join(format!("{day}.csv"))
+    }
+}
+
+#[async_trait]
+impl Sink for CsvSink {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    fn interval(&self) -> i64 {
+        self.interval
+    }
+
+    async fn write(&self, timestamp: i64, data: &[Aggregate]) -> anyhow::Result<()> {
+        let mut rows = String::new();
+        for a in data {
+            let s = &a.stats;
+            rows.push_str(&format!(
+                "{timestamp},{},{},{},{},{},{},{},{}\n",
+                quote(&a.sensor_id),
+                quote(&a.quantity),
+                s.count,
+                field(s.mean),
+                field(s.min),
+                field(s.max),
+                field(s.stddev),
+                field(s.ewma)
+            ));
+        }
+
+        let path = self.path(timestamp);
+        append(&path, &rows)?;
+        info!(
+            "****** CSV: wrote {} rows to {}",
+            data.len(),
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+fn quote(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// NaN stands for no value and is left empty
+fn field(value: f64) -> String {
+    if value.is_nan() {
+        String::new()
+    } else {
+        value.to_string()
+    }
+}
+
+fn append(path: &Path, rows: &str) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(HEADER.as_bytes())?;
+    }
+    file.write_all(rows.as_bytes())?;
+    Ok(())
+}
+// EOF
@@ -0,0 +1,300 @@
+// filter.rs
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::SystemTime,
+};
+
+use super::config;
+use super::tbuf::Tdata;
+
+// Why a sample was rejected on ingest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reject {
+    Range,
+    Rate,
+    Outlier,
+    Future,
+    Past,
+    NotFinite,
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reject::Range => write!(f, "out of range"),
+            Reject::Rate => write!(f, "changing too fast"),
+            Reject::Outlier => write!(f, "outlier"),
+            Reject::Future => write!(f, "timestamp in the future"),
+            Reject::Past => write!(f, "timestamp too old"),
+            Reject::NotFinite => write!(f, "not a finite number"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FilterState {
+    // last accepted sample
+    last: Option<(SystemTime, f64)>,
+    // recent in-range values, accepted or not
+    history: VecDeque<f64>,
+    rejected: u64,
+}
+
+#[derive(Debug, Default)]
+struct FilterConfig {
+    min: Option<f64>,
+    max: Option<f64>,
+    max_rate: Option<f64>,
+    window: usize,
+    hampel_k: f64,
+    min_dev: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct SampleFilter {
+    config: FilterConfig,
+    state: HashMap<String, FilterState>,
+}
+
+impl SampleFilter {
+    pub fn new(opts: &config::OptsCommon) -> Self {
+        SampleFilter {
+            config: FilterConfig {
+                min: opts.filter_min,
+                max: opts.filter_max,
+                max_rate: opts.filter_max_rate,
+                window: opts.filter_window,
+                hampel_k: opts.filter_hampel_k,
+                min_dev: opts.filter_min_dev,
+            },
+            state: HashMap::new(),
+        }
+    }
+
+    pub fn forget<S: AsRef<str>>(&mut self, sensor_id: S) {
+        self.state.remove(sensor_id.as_ref());
+    }
+
+    // Number of samples rejected from a sensor so far
+    pub fn rejected<S: AsRef<str>>(&self, sensor_id: S) -> u64 {
+        self.state
+            .get(sensor_id.as_ref())
+            .map_or(0, |state| state.rejected)
+    }
+
+    pub fn check<S: AsRef<str>>(&mut self, sensor_id: S, tdata: &Tdata) -> Result<(), Reject> {
+        if !self.state.contains_key(sensor_id.as_ref()) {
+            self.state
+                .insert(sensor_id.as_ref().into(), FilterState::default());
+        }
+        let Some(state) = self.state.get_mut(sensor_id.as_ref()) else {
+            return Ok(());
+        };
+
+        let res = self.config.check(state, tdata);
+        match res {
+            // late samples do not move the rate check reference
+            Ok(()) if state.last.is_none_or(|(ts, _)| ts <= tdata.ts()) => {
+                state.last = Some((tdata.ts(), tdata.data()))
+            }
+            Ok(()) => {}
+            Err(_) => state.rejected += 1,
+        }
+        res
+    }
+}
+
+impl FilterConfig {
+    fn check(&self, state: &mut FilterState, tdata: &Tdata) -> Result<(), Reject> {
+        let value = tdata.data();
+
+        // plausible range, e.g. DS18B20 read errors are 85.0 and -127.0
+        if !value.is_finite()
+            || self.min.is_some_and(|min| value < min)
+            || self.max.is_some_and(|max| value > max)
+        {
+            return Err(Reject::Range);
+        }
+
+        // Hampel filter against the median of the last window values.
+        // Rejected values go into the history as well, so that a real
+        // step change is accepted once it has lasted for half a window.
+        if self.window > 0 {
+            let outlier = state.history.len() >= self.window && {
+                let med = median(state.history.iter().copied());
+                let mad = median(state.history.iter().map(|v| (v - med).abs()));
+                (value - med).abs() > (self.hampel_k * 1.4826 * mad).max(self.min_dev)
+            };
+            if state.history.len() >= self.window {
+                state.history.pop_front();
+            }
+            state.history.push_back(value);
+            if outlier {
+                return Err(Reject::Outlier);
+            }
+        }
+
+        // max rate of change per second since the last accepted sample
+        if let (Some(max_rate), Some((last_ts, last_value))) = (self.max_rate, state.last) {
+            let dt = tdata
+                .ts()
+                .duration_since(last_ts)
+                .unwrap_or_default()
+                .as_secs_f64();
+            if dt > 0.0 && (value - last_value).abs() / dt > max_rate {
+                return Err(Reject::Rate);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn median<I: Iterator<Item = f64>>(values: I) -> f64 {
+    let mut v = values.collect::<Vec<f64>>();
+    if v.is_empty() {
+        return f64::NAN;
+    }
+    v.sort_by(f64::total_cmp);
+    let mid = v.len() / 2;
+    if v.len() % 2 == 0 {
+        (v[mid - 1] + v[mid]) / 2.0
+    } else {
+        v[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn filter(config: FilterConfig) -> SampleFilter {
+        SampleFilter {
+            config,
+            state: HashMap::new(),
+        }
+    }
+
+    fn hampel(min_dev: f64) -> SampleFilter {
+        filter(FilterConfig {
+            window: 5,
+            hampel_k: 3.0,
+            min_dev,
+            ..Default::default()
+        })
+    }
+
+    // value at t seconds from a fixed start
+    fn sample(t: u64, value: f64) -> Tdata {
+        Tdata::new((
+            value,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + t),
+        ))
+    }
+
+    fn check_all(filter: &mut SampleFilter, samples: &[(u64, f64)]) -> Vec<Result<(), Reject>> {
+        samples
+            .iter()
+            .map(|&(t, v)| filter.check("abc", &sample(t, v)))
+            .collect()
+    }
+
+    const STEADY: [(u64, f64); 5] = [(0, 20.0), (1, 20.1), (2, 20.0), (3, 20.1), (4, 20.0)];
+
+    #[test]
+    fn range() {
+        let mut f = filter(FilterConfig {
+            min: Some(-55.0),
+            max: Some(84.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            check_all(&mut f, &[(0, 85.0), (1, -127.0), (2, 21.0)]),
+            vec![Err(Reject::Range), Err(Reject::Range), Ok(())]
+        );
+    }
+
+    #[test]
+    fn hampel_outlier() {
+        let mut f = hampel(0.5);
+        assert!(check_all(&mut f, &STEADY).iter().all(|r| r.is_ok()));
+        // the MAD is 0, the min_dev floor lets small changes through
+        assert_eq!(f.check("abc", &sample(5, 20.4)), Ok(()));
+        assert_eq!(f.check("abc", &sample(6, 25.0)), Err(Reject::Outlier));
+    }
+
+    #[test]
+    fn hampel_min_dev_floor() {
+        // without the floor a MAD of 0 rejects any change at all
+        let mut f = hampel(0.0);
+        check_all(
+            &mut f,
+            &[(0, 20.0), (1, 20.0), (2, 20.0), (3, 20.0), (4, 20.0)],
+        );
+        assert_eq!(f.check("abc", &sample(5, 20.4)), Err(Reject::Outlier));
+    }
+
+    #[test]
+    fn step_change_accepted() {
+        let mut f = hampel(0.5);
+        check_all(&mut f, &STEADY);
+        // rejected until the new level is the majority of the window
+        assert_eq!(
+            check_all(
+                &mut f,
+                &[(5, 30.0), (6, 30.0), (7, 30.0), (8, 30.0), (9, 30.1)]
+            ),
+            vec![
+                Err(Reject::Outlier),
+                Err(Reject::Outlier),
+                Err(Reject::Outlier),
+                Ok(()),
+                Ok(())
+            ]
+        );
+    }
+
+    #[test]
+    fn rate() {
+        let mut f = filter(FilterConfig {
+            max_rate: Some(0.1),
+            ..Default::default()
+        });
+        assert_eq!(
+            check_all(&mut f, &[(0, 20.0), (10, 25.0), (10, 20.5), (20, 21.0)]),
+            vec![Ok(()), Err(Reject::Rate), Ok(()), Ok(())]
+        );
+    }
+
+    #[test]
+    fn late_sample_keeps_rate_reference() {
+        let mut f = filter(FilterConfig {
+            max_rate: Some(0.1),
+            ..Default::default()
+        });
+        assert_eq!(f.check("abc", &sample(100, 20.0)), Ok(()));
+        // a late sample is not rate checked, and must not become the reference
+        assert_eq!(f.check("abc", &sample(50, 30.0)), Ok(()));
+        assert_eq!(f.check("abc", &sample(101, 20.05)), Ok(()));
+    }
+
+    #[test]
+    fn rejected_counter() {
+        let mut f = filter(FilterConfig {
+            max: Some(84.0),
+            ..Default::default()
+        });
+        check_all(&mut f, &[(0, 85.0), (1, 21.0), (2, 85.0), (3, 85.0)]);
+        f.check("def", &sample(4, 85.0)).unwrap_err();
+        assert_eq!(f.rejected("abc"), 3);
+        assert_eq!(f.rejected("def"), 1);
+        assert_eq!(f.rejected("xyz"), 0);
+        f.forget("abc");
+        assert_eq!(f.rejected("abc"), 0);
+    }
+}
+// EOF
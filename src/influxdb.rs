@@ -1,98 +1,436 @@
 // influxdb.rs
 
-use std::sync::Arc;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
+use async_trait::async_trait;
 use chrono::*;
-use futures::stream;
-use influxdb2::{api::write::TimestampPrecision, models::DataPoint, Client};
+use clap::ValueEnum;
+use influxdb2::models::{DataPoint, WriteDataPoint};
+use reqwest::{header, StatusCode};
 use tokio::time::{sleep, Duration};
 use tracing::*;
 
 use super::config;
+use super::retry::RetryPolicy;
+use super::sensordata::TEMPERATURE;
+use super::sink::{Aggregate, Sink};
+use super::spool::Spool;
 use crate::*;
 
+// Write endpoint and authentication of the database
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DbApi {
+    // InfluxDB 2.x /api/v2/write with token, org and bucket
+    #[default]
+    V2,
+    // InfluxDB 1.x /write with database and basic auth
+    V1,
+}
+
 #[derive(Clone)]
 pub struct InfluxSender {
     mystate: Arc<ServerState>,
     interval: i64,
+    api: DbApi,
     url: String,
     token: String,
     org: String,
     bucket: String,
+    database: String,
+    retention_policy: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
     measurement: String,
+    ewma: bool,
+    spool: Option<Spool>,
+    retry: RetryPolicy,
+    http: reqwest::Client,
 }
 
 impl InfluxSender {
-    pub fn new(opts: &config::OptsCommon, mystate: Arc<ServerState>) -> Self {
-        InfluxSender {
+    pub fn new(opts: &config::OptsCommon, mystate: Arc<ServerState>) -> anyhow::Result<Self> {
+        Ok(InfluxSender {
             mystate,
             interval: opts.send_interval,
+            api: opts.db_api,
             url: opts.db_url.clone(),
             token: opts.token.clone(),
             org: opts.org.clone(),
             bucket: opts.bucket.clone(),
+            database: opts.database.clone(),
+            retention_policy: opts.db_rp.clone(),
+            user: opts.db_user.clone(),
+            password: opts.db_password.clone(),
             measurement: opts.measurement.clone(),
+            ewma: opts.db_ewma,
+            spool: Spool::new(opts)?,
+            retry: RetryPolicy::new(opts),
+            // a hung connection has to turn into an error to be retried
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(opts.db_timeout))
+                .build()?,
+        })
+    }
+
+    // One point per aggregate, in line protocol
+    fn line_protocol(&self, timestamp: i64, data: &[Aggregate]) -> anyhow::Result<Vec<u8>> {
+        let mut points = Vec::with_capacity(data.len());
+        for a in data {
+            // temperature points keep the tags they always had, other
+            // quantities are told apart by a tag so that clients cannot
+            // create measurements of their own
+            let mut point =
+                DataPoint::builder(&self.measurement).tag("sensor", a.sensor_id.as_str());
+            if a.quantity != TEMPERATURE {
+                point = point.tag("quantity", a.quantity.as_str());
+            }
+            for (k, v) in a.tags.iter() {
+                point = point.tag(k, v);
+            }
+            let stats = &a.stats;
+            point = point.field("count", stats.count as i64);
+            // NaN stands for no value, and InfluxDB refuses the whole batch if
+            // it gets one, e.g. min and max of an empty window in hold mode
+            for (name, value, wanted) in [
+                ("value", stats.mean, true),
+                ("min", stats.min, true),
+                ("max", stats.max, true),
+                ("stddev", stats.stddev, true),
+                ("ewma", stats.ewma, self.ewma),
+            ] {
+                if wanted && value.is_finite() {
+                    point = point.field(name, value);
+                }
+            }
+            points.push(point.timestamp(timestamp).build()?);
+        }
+
+        debug!("influxdb data: {points:?}");
+        let mut batch = Vec::new();
+        for point in points {
+            point.write_data_point_to(&mut batch)?;
         }
+        Ok(batch)
     }
 
-    pub async fn run_db_send(self) {
-        loop {
-            let sender_i = self.clone();
-            if let Err(e) = sender_i.db_send().await {
-                error!("db_send() failed: {e}");
+    // Spooled batches go first to keep the order, and the new batch is
+    // spooled as well if they could not be sent
+    async fn send_batch(&self, batch: Vec<u8>, n_points: usize) {
+        let counters = &self.mystate.db_counters;
+        if let Some(spool) = &self.spool
+            && !self.replay(spool).await
+        {
+            self.spool_batch(spool, &batch, n_points);
+            return;
+        }
+
+        match self.write_retry(&batch).await {
+            Ok(()) => {
+                counters
+                    .written
+                    .fetch_add(n_points as u64, Ordering::Relaxed);
+                info!("****** InfluxDB: inserted {n_points} points")
+            }
+            Err(e) => {
+                error!("InfluxDB write failed: {e}");
+                match &self.spool {
+                    Some(spool) if e.retryable() => self.spool_batch(spool, &batch, n_points),
+                    _ => {
+                        counters
+                            .dropped
+                            .fetch_add(n_points as u64, Ordering::Relaxed);
+                        error!("****** InfluxDB: lost {n_points} points, {counters}");
+                    }
+                }
             }
-            error!("InfluxDB task exited, should not happen");
-            sleep(Duration::new(10, 0)).await;
-            error!("Restarting InfluxDB tasK...");
         }
     }
 
-    // keep sending data into database
-    async fn db_send(self) -> anyhow::Result<()> {
-        let url = self.url.clone();
-        let token = self.token.clone();
-        let org = self.org.clone();
-        let bucket = self.bucket.clone();
+    // Send the spooled batches oldest first, true if the spool is now empty
+    async fn replay(&self, spool: &Spool) -> bool {
+        let counters = &self.mystate.db_counters;
+        let pending = match spool.pending() {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Cannot read spool: {e}");
+                return false;
+            }
+        };
+        for path in pending {
+            let batch = match spool.read(&path) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Cannot read {}: {e}", path.display());
+                    return false;
+                }
+            };
+            let n_points = batch
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .count() as u64;
+            match self.write_retry(&batch).await {
+                Ok(()) => {
+                    counters.written.fetch_add(n_points, Ordering::Relaxed);
+                    info!("****** InfluxDB: replayed {}", path.display());
+                }
+                Err(e) if e.retryable() => {
+                    error!("InfluxDB still unavailable: {e}");
+                    return false;
+                }
+                Err(e) => {
+                    counters.dropped.fetch_add(n_points, Ordering::Relaxed);
+                    error!("InfluxDB rejected {}, dropped: {e}", path.display());
+                }
+            }
+            if let Err(e) = spool.remove(&path) {
+                error!("Cannot remove {}: {e}", path.display());
+                return false;
+            }
+        }
+        true
+    }
 
+    fn spool_batch(&self, spool: &Spool, batch: &[u8], n_points: usize) {
+        match spool.push(batch) {
+            Ok(()) => info!("****** InfluxDB: spooled {n_points} points"),
+            Err(e) => {
+                let counters = &self.mystate.db_counters;
+                counters
+                    .dropped
+                    .fetch_add(n_points as u64, Ordering::Relaxed);
+                error!("Cannot spool {n_points} points: {e}, {counters}");
+            }
+        }
+    }
+
+    // Write, retrying with backoff as long as the error is retryable
+    async fn write_retry(&self, batch: &[u8]) -> Result<(), WriteError> {
+        let counters = &self.mystate.db_counters;
+        let mut attempt = 0;
         loop {
-            let waitsec = self.interval - (Utc::now().timestamp() % self.interval);
-            // wait until next interval start
-            sleep(Duration::new(waitsec as u64, 0)).await;
-
-            let timestamp = Utc::now().timestamp();
-            // in case we overslept :D
-            let timestamp_i = timestamp - (timestamp % self.interval);
-
-            let mut points = Vec::with_capacity(16);
-
-            for datapoint in self.mystate.mydata.averages_db().await {
-                points.push(
-                    DataPoint::builder(&self.measurement)
-                        .tag("sensor", datapoint.0.as_str())
-                        .field("value", datapoint.1)
-                        .timestamp(timestamp_i)
-                        .build()?,
-                );
+            let Err(e) = self.write(batch.to_vec()).await else {
+                return Ok(());
+            };
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+            attempt += 1;
+            if !e.retryable() || attempt >= self.retry.max_attempts {
+                return Err(e);
             }
+            // a long Retry-After would stall the sink, so it is capped as well
+            let delay = e
+                .retry_after()
+                .map(|delay| delay.min(self.retry.max))
+                .unwrap_or_else(|| self.retry.backoff(attempt));
+            counters.retries.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "InfluxDB write failed: {e}, retry {attempt} in {:.1} s",
+                delay.as_secs_f64()
+            );
+            sleep(delay).await;
+        }
+    }
 
-            if !points.is_empty() {
-                debug!("influxdb data: {points:?}");
-                let n_points = points.len();
-                let influx_client = Client::new(&url, &org, &token);
-                match influx_client
-                    .write_with_precision(
-                        &bucket,
-                        stream::iter(points),
-                        TimestampPrecision::Seconds,
-                    )
-                    .await
-                {
-                    Ok(_) => info!("****** InfluxDB: inserted {n_points} points"),
-                    Err(e) => error!("InfluxDB client error: {e:?}"),
+    async fn write(&self, batch: Vec<u8>) -> Result<(), WriteError> {
+        let url = self.url.trim_end_matches('/');
+        let request = match self.api {
+            DbApi::V2 => self
+                .http
+                .post(format!("{url}/api/v2/write"))
+                .query(&[
+                    ("org", self.org.as_str()),
+                    ("bucket", self.bucket.as_str()),
+                    ("precision", "s"),
+                ])
+                .header(header::AUTHORIZATION, format!("Token {}", self.token)),
+            DbApi::V1 => {
+                let mut request = self
+                    .http
+                    .post(format!("{url}/write"))
+                    .query(&[("db", self.database.as_str()), ("precision", "s")]);
+                if let Some(rp) = &self.retention_policy {
+                    request = request.query(&[("rp", rp)]);
+                }
+                match &self.user {
+                    Some(user) => request.basic_auth(user, self.password.as_ref()),
+                    None => request,
                 }
             }
+        };
+        let resp = request
+            .body(batch)
+            .send()
+            .await
+            .map_err(WriteError::Transport)?;
+        if resp.status().is_success() {
+            return Ok(());
+        }
+
+        let status = resp.status();
+        let retry_after = resp
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        let text = resp.text().await.unwrap_or_default();
+        Err(WriteError::Http {
+            status,
+            retry_after,
+            text,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSender {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    fn interval(&self) -> i64 {
+        self.interval
+    }
+
+    async fn write(&self, timestamp: i64, data: &[Aggregate]) -> anyhow::Result<()> {
+        let batch = self.line_protocol(timestamp, data)?;
+        self.send_batch(batch, data.len()).await;
+        Ok(())
+    }
+}
+
+// Retry-After is either seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+// Database write statistics, in points except for failures and retries
+#[derive(Debug, Default)]
+pub struct DbCounters {
+    pub written: AtomicU64,
+    pub dropped: AtomicU64,
+    // failed write requests
+    pub failures: AtomicU64,
+    pub retries: AtomicU64,
+}
+
+impl fmt::Display for DbCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "written={} dropped={} failures={} retries={}",
+            self.written.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    Transport(reqwest::Error),
+    Http {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        text: String,
+    },
+}
+
+impl WriteError {
+    // Worth trying again later, unlike the database refusing the data
+    pub fn retryable(&self) -> bool {
+        match self {
+            WriteError::Transport(_) => true,
+            WriteError::Http { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
         }
     }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            WriteError::Http { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Transport(e) => write!(f, "{e}"),
+            WriteError::Http { status, text, .. } => write!(f, "HTTP {status}: {text}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::sensordata::MyData;
+    use crate::tbuf::WindowStats;
+
+    #[test]
+    fn non_finite_fields_skipped() {
+        let sender = sender();
+        let aggregate = Aggregate {
+            sensor_id: "abc".into(),
+            quantity: TEMPERATURE.into(),
+            tags: Vec::new(),
+            // an empty window in hold mode
+            stats: WindowStats {
+                mean: 21.5,
+                ..Default::default()
+            },
+        };
+        let batch = sender.line_protocol(1_700_000_000, &[aggregate]).unwrap();
+        assert_eq!(
+            String::from_utf8(batch).unwrap(),
+            "temperature,sensor=abc count=0i,value=21.5 1700000000\n"
+        );
+    }
+
+    #[test]
+    fn quantity_tag() {
+        let aggregate = Aggregate {
+            sensor_id: "abc".into(),
+            quantity: "humidity".into(),
+            tags: Vec::new(),
+            stats: WindowStats {
+                count: 1,
+                mean: 45.0,
+                ..Default::default()
+            },
+        };
+        let batch = sender().line_protocol(1_700_000_000, &[aggregate]).unwrap();
+        assert_eq!(
+            String::from_utf8(batch).unwrap(),
+            "temperature,quantity=humidity,sensor=abc count=1i,value=45 1700000000\n"
+        );
+    }
+
+    fn sender() -> InfluxSender {
+        let opts = config::OptsCommon::parse_from(["test"]);
+        let mystate = Arc::new(ServerState {
+            mydata: MyData::new(&opts).unwrap(),
+            counter: AtomicU64::new(0),
+            ingest_errors: AtomicU64::new(0),
+            admin_token: None,
+            db_counters: Default::default(),
+        });
+        InfluxSender::new(&opts, mystate).unwrap()
+    }
 }
 
 // EOF
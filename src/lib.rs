@@ -1,18 +1,35 @@
 // lib.rs
 
+use crate::influxdb::DbCounters;
 use crate::sensordata::MyData;
 pub use config::*;
 use std::sync::atomic;
 
 pub mod config;
 
+pub mod calibration;
+pub mod csvsink;
+pub mod filter;
 pub mod influxdb;
+pub mod linkformat;
+pub mod metrics;
+pub mod payload;
+pub mod retry;
 pub mod sensordata;
+pub mod senml;
+pub mod sensormeta;
+pub mod sink;
+pub mod spool;
 pub mod tbuf;
 
 pub struct ServerState {
     pub mydata: MyData,
     pub counter: atomic::AtomicU64,
+    // invalid payloads and rejected readings
+    pub ingest_errors: atomic::AtomicU64,
+    // required for deleting and renaming sensors
+    pub admin_token: Option<String>,
+    pub db_counters: DbCounters,
 }
 
 // EOF
@@ -0,0 +1,73 @@
+// linkformat.rs
+
+// CoRE Link Format (RFC 6690) for /.well-known/core
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    href: String,
+    // attributes without a value, like obs, have None
+    attrs: Vec<(String, Option<String>)>,
+}
+
+impl Link {
+    pub fn new<S: Into<String>>(href: S) -> Self {
+        Link {
+            href: href.into(),
+            attrs: Vec::new(),
+        }
+    }
+
+    pub fn attr<S: Into<String>>(mut self, name: &str, value: S) -> Self {
+        self.attrs.push((name.into(), Some(value.into())));
+        self
+    }
+
+    pub fn flag(mut self, name: &str) -> Self {
+        self.attrs.push((name.into(), None));
+        self
+    }
+
+    // Query filtering as in RFC 6690 section 4.1: the href or an attribute
+    // must match the value, which may end with a '*' wildcard.
+    // Attributes holding several space-separated values match if any does.
+    pub fn matches(&self, name: &str, pattern: &str) -> bool {
+        let value_matches = |value: &str| match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => value == pattern,
+        };
+        if name == "href" {
+            return value_matches(&self.href);
+        }
+        self.attrs
+            .iter()
+            .filter(|(n, _)| n == name)
+            .any(|(_, v)| match v {
+                Some(v) => v.split_whitespace().any(value_matches),
+                None => pattern.is_empty(),
+            })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}>", self.href)?;
+        for (name, value) in self.attrs.iter() {
+            match value {
+                Some(v) => write!(f, ";{name}=\"{v}\"")?,
+                None => write!(f, ";{name}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn format(links: &[Link]) -> String {
+    links
+        .iter()
+        .map(|link| link.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+// EOF
@@ -0,0 +1,182 @@
+// metrics.rs
+
+// Prometheus text exposition format over a minimal HTTP/1 listener
+
+use std::{
+    fmt::Write as _,
+    sync::{atomic::Ordering, Arc},
+    time::SystemTime,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
+};
+use tracing::*;
+
+use crate::sensordata::SensorStatus;
+use crate::*;
+
+const PREFIX: &str = "coap_server_temp";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+// longest request head we read, the rest is ignored
+const MAX_REQUEST: u64 = 8192;
+
+// Per sensor quantity value of a gauge
+type Gauge = fn(&SensorStatus, SystemTime) -> f64;
+
+// Bind now so that a bad address fails the startup, then serve in the background
+pub async fn start(listen: &str, mystate: Arc<ServerState>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!("Serving metrics on http://{listen}/metrics");
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle(stream, mystate.clone()));
+                }
+                Err(e) => error!("Metrics accept failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle(stream: TcpStream, mystate: Arc<ServerState>) {
+    let peer = stream
+        .peer_addr()
+        .map_or_else(|_| "<none>".into(), |a| a.to_string());
+    match timeout(Duration::from_secs(10), serve(stream, &mystate)).await {
+        Ok(Ok(())) => debug!("Metrics request from {peer}"),
+        Ok(Err(e)) => debug!("Metrics request from {peer} failed: {e}"),
+        Err(_) => debug!("Metrics request from {peer} timed out"),
+    }
+}
+
+async fn serve(stream: TcpStream, mystate: &ServerState) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream).take(MAX_REQUEST);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // the headers are not needed, only read past them
+    let mut line = String::new();
+    while stream.read_line(&mut line).await? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(mystate).await),
+        (Some("GET"), _) => ("404 Not Found", "NOT FOUND\n".into()),
+        _ => ("405 Method Not Allowed", "METHOD NOT ALLOWED\n".into()),
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let stream = stream.get_mut().get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn render(mystate: &ServerState) -> String {
+    let mut out = String::new();
+
+    let db = &mystate.db_counters;
+    for (name, help, value) in [
+        ("requests_total", "CoAP requests received", &mystate.counter),
+        (
+            "ingest_errors_total",
+            "Invalid payloads and rejected readings",
+            &mystate.ingest_errors,
+        ),
+        (
+            "db_points_written_total",
+            "Points written into InfluxDB",
+            &db.written,
+        ),
+        (
+            "db_points_dropped_total",
+            "Points that could not be written into InfluxDB",
+            &db.dropped,
+        ),
+        (
+            "db_write_failures_total",
+            "Failed InfluxDB write requests",
+            &db.failures,
+        ),
+        (
+            "db_write_retries_total",
+            "Retried InfluxDB write requests",
+            &db.retries,
+        ),
+    ] {
+        header(&mut out, name, help, "counter");
+        let _ = writeln!(out, "{PREFIX}_{name} {}", value.load(Ordering::Relaxed));
+    }
+
+    let status = mystate.mydata.status().await;
+    let now = SystemTime::now();
+    let gauges: [(&str, &str, Gauge); 4] = [
+        (
+            "sensor_average",
+            "Average over the database averaging window",
+            |s, _| s.stats.mean,
+        ),
+        (
+            "sensor_samples",
+            "Samples in the database averaging window",
+            |s, _| s.stats.count as f64,
+        ),
+        (
+            "sensor_last_seen_seconds",
+            "Seconds since the latest sample",
+            |s, now| {
+                s.last_seen
+                    .and_then(|ts| now.duration_since(ts).ok())
+                    .map_or(f64::NAN, |age| age.as_secs_f64())
+            },
+        ),
+        (
+            "sensor_stale",
+            "1 if the sensor has been silent for too long",
+            |s, _| if s.stale { 1.0 } else { 0.0 },
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, help, "gauge");
+        for s in status.iter() {
+            let _ = writeln!(
+                out,
+                "{PREFIX}_{name}{{sensor=\"{}\",quantity=\"{}\"}} {}",
+                escape(&s.sensor_id),
+                escape(&s.quantity),
+                number(value(s, now))
+            );
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn number(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "NaN".into(),
+        v if v == f64::INFINITY => "+Inf".into(),
+        v if v == f64::NEG_INFINITY => "-Inf".into(),
+        v => v.to_string(),
+    }
+}
+// EOF
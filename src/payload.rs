@@ -0,0 +1,321 @@
+// payload.rs
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::sensordata::TEMPERATURE;
+
+const MAX_NAME: usize = 64;
+
+// Readings of one sensor from an ingest payload
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SensorReadings {
+    pub id: String,
+    // temperature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub t: Option<f32>,
+    // unix timestamp in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<i64>,
+    // other quantities
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, f32>,
+    // index of the item in the payload array, for the reply
+    #[serde(skip)]
+    pub index: Option<usize>,
+}
+
+impl SensorReadings {
+    // Return (quantity, value) pairs
+    pub fn readings(&self) -> Vec<(String, f32)> {
+        let mut readings = Vec::with_capacity(1 + self.values.len());
+        if let Some(t) = self.t {
+            readings.push((TEMPERATURE.to_string(), t));
+        }
+        for (quantity, value) in self.values.iter() {
+            readings.push((quantity.clone(), *value));
+        }
+        readings
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        if !valid_name(&self.id) {
+            return Err("invalid sensor id");
+        }
+        if self.t.is_none() && self.values.is_empty() {
+            return Err("no values");
+        }
+        if !self.values.keys().all(|q| valid_name(q)) {
+            return Err("invalid quantity");
+        }
+        if self.readings().iter().any(|(_, v)| !v.is_finite()) {
+            return Err("invalid number");
+        }
+        Ok(())
+    }
+}
+
+// Sensor ids and quantity names end up in CoAP paths, CSV files and
+// InfluxDB tags, so only letters, digits and "-_.:" are allowed
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+// Reply to an ingest request, "ok" if any of the readings were accepted
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct IngestStatus {
+    pub status: String,
+    pub accepted: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rejected: Vec<PayloadError>,
+}
+
+impl IngestStatus {
+    // Results of storing the readings, in the same order
+    pub fn new<E: ToString>(readings: &[SensorReadings], results: &[Result<(), E>]) -> Self {
+        let rejected = readings
+            .iter()
+            .zip(results)
+            .filter_map(|(r, res)| {
+                res.as_ref()
+                    .err()
+                    .map(|e| PayloadError::new(e.to_string(), r.index))
+            })
+            .collect::<Vec<_>>();
+        let accepted = results.len() - rejected.len();
+        IngestStatus {
+            status: if accepted > 0 { "ok" } else { "error" }.into(),
+            accepted,
+            rejected,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.accepted > 0
+    }
+}
+
+// Error returned to the client, serialized as is
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PayloadError {
+    pub error: String,
+    // index of the offending item in an array
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+impl PayloadError {
+    pub(crate) fn new<S: ToString>(error: S, index: Option<usize>) -> Self {
+        PayloadError {
+            error: error.to_string(),
+            index,
+        }
+    }
+}
+
+// Parse "<id> <temp> [<timestamp>]"
+// or "<id> [<temp>] <quantity>=<value> [<quantity>=<value> ...] [<timestamp>]"
+pub fn parse_text(payload: &str) -> Result<SensorReadings, &'static str> {
+    let indata = payload.split_whitespace().collect::<Vec<&str>>();
+    if indata.len() < 2 {
+        return Err("INVALID DATA");
+    }
+    if !valid_name(indata[0]) {
+        return Err("INVALID SENSOR ID");
+    }
+    let mut readings = SensorReadings {
+        id: indata[0].to_string(),
+        ..Default::default()
+    };
+
+    let mut items = &indata[1..];
+    if !items[0].contains('=') {
+        match items[0].parse::<f32>() {
+            Ok(temp) if temp.is_finite() => readings.t = Some(temp),
+            _ => return Err("INVALID NUMBER"),
+        }
+        items = &items[1..];
+    }
+    if let Some((last, rest)) = items.split_last()
+        && !last.contains('=')
+    {
+        match last.parse::<i64>() {
+            Ok(ts) => readings.ts = Some(ts),
+            Err(_) => return Err("INVALID TIMESTAMP"),
+        }
+        items = rest;
+    }
+
+    for item in items {
+        let Some((quantity, value)) = item.split_once('=') else {
+            return Err("INVALID DATA");
+        };
+        if !valid_name(quantity) {
+            return Err("INVALID QUANTITY");
+        }
+        match value.parse::<f32>() {
+            Ok(value) if value.is_finite() => readings.values.insert(quantity.to_string(), value),
+            _ => return Err("INVALID NUMBER"),
+        };
+    }
+    if readings.t.is_none() && readings.values.is_empty() {
+        return Err("INVALID DATA");
+    }
+    Ok(readings)
+}
+
+// Parse one reading per line, see parse_text(). Empty lines are skipped.
+pub fn parse_lines(payload: &str) -> Vec<Result<SensorReadings, &'static str>> {
+    payload
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_text)
+        .collect()
+}
+
+// Parse a JSON object like {"id":"abc","t":21.5,"ts":1700000000}
+// or an array of them
+pub fn parse_json(payload: &[u8]) -> Result<Vec<SensorReadings>, PayloadError> {
+    let value = serde_json::from_slice::<serde_json::Value>(payload)
+        .map_err(|e| PayloadError::new(format!("invalid json: {e}"), None))?;
+
+    match value {
+        serde_json::Value::Array(items) => collect_items(
+            items
+                .into_iter()
+                .map(|item| serde_json::from_value(item).map_err(|e| e.to_string())),
+            true,
+        ),
+        item => collect_items(
+            [serde_json::from_value(item).map_err(|e| e.to_string())],
+            false,
+        ),
+    }
+}
+
+// Parse a CBOR map with the same fields as the JSON objects, or an array of them
+pub fn parse_cbor(payload: &[u8]) -> Result<Vec<SensorReadings>, PayloadError> {
+    let value = ciborium::from_reader::<ciborium::Value, _>(payload)
+        .map_err(|e| PayloadError::new(format!("invalid cbor: {e}"), None))?;
+
+    match value {
+        ciborium::Value::Array(items) => collect_items(
+            items
+                .iter()
+                .map(|item| item.deserialized().map_err(cbor_error)),
+            true,
+        ),
+        item => collect_items([item.deserialized().map_err(cbor_error)], false),
+    }
+}
+
+// The Display of ciborium errors is their Debug output
+pub(crate) fn cbor_error(e: ciborium::value::Error) -> String {
+    let ciborium::value::Error::Custom(e) = e;
+    e
+}
+
+fn collect_items<I>(items: I, is_array: bool) -> Result<Vec<SensorReadings>, PayloadError>
+where
+    I: IntoIterator<Item = Result<SensorReadings, String>>,
+{
+    let mut readings = Vec::new();
+    for (i, item) in items.into_iter().enumerate() {
+        let index = if is_array { Some(i) } else { None };
+        let mut item = item.map_err(|e| PayloadError::new(e, index))?;
+        item.index = index;
+        item.validate().map_err(|e| PayloadError::new(e, index))?;
+        readings.push(item);
+    }
+    if readings.is_empty() {
+        return Err(PayloadError::new("no data", None));
+    }
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_rejects_non_finite() {
+        for payload in ["abc inf", "abc -inf", "abc NaN", "abc 21.5 humidity=nan"] {
+            assert_eq!(parse_text(payload), Err("INVALID NUMBER"), "{payload}");
+        }
+        assert!(parse_text("abc 21.5 humidity=45").is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_non_finite() {
+        let reading = SensorReadings {
+            id: "abc".into(),
+            values: BTreeMap::from([("humidity".to_string(), f32::NAN)]),
+            ..Default::default()
+        };
+        assert_eq!(reading.validate(), Err("invalid number"));
+        let reading = SensorReadings {
+            id: "abc".into(),
+            t: Some(f32::INFINITY),
+            ..Default::default()
+        };
+        assert_eq!(reading.validate(), Err("invalid number"));
+    }
+
+    #[test]
+    fn ingest_status() {
+        let readings = parse_json(br#"[{"id":"abc","t":21.5},{"id":"def","t":200}]"#).unwrap();
+        let status = IngestStatus::new(&readings, &[Ok(()), Err("out of range")]);
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"status":"ok","accepted":1,"rejected":[{"error":"out of range","index":1}]}"#
+        );
+        let readings = parse_json(br#"{"id":"def","t":200}"#).unwrap();
+        let status = IngestStatus::new(&readings, &[Err("out of range")]);
+        assert!(!status.is_ok());
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"status":"error","accepted":0,"rejected":[{"error":"out of range"}]}"#
+        );
+    }
+
+    #[test]
+    fn names_restricted() {
+        for name in ["28-00000a1b2c3d", "aa:bb:cc:dd:ee:ff", "pm2.5", "co_2"] {
+            assert!(valid_name(name), "{name}");
+        }
+        for name in [
+            "",
+            "a/b",
+            "a,b",
+            "a\"b",
+            "a b",
+            "a\nb",
+            "a=b",
+            "ä",
+            &"x".repeat(65),
+        ] {
+            assert!(!valid_name(name), "{name}");
+        }
+        assert_eq!(parse_text("a/b 21.5"), Err("INVALID SENSOR ID"));
+        assert_eq!(parse_text("abc 21.5 hum,x=45"), Err("INVALID QUANTITY"));
+        assert_eq!(parse_text("abc =45"), Err("INVALID QUANTITY"));
+        let reading = SensorReadings {
+            id: "abc".into(),
+            values: BTreeMap::from([("a\"b".to_string(), 1.0)]),
+            ..Default::default()
+        };
+        assert_eq!(reading.validate(), Err("invalid quantity"));
+        let reading = SensorReadings {
+            id: "a/b".into(),
+            t: Some(21.5),
+            ..Default::default()
+        };
+        assert_eq!(reading.validate(), Err("invalid sensor id"));
+    }
+}
+// EOF
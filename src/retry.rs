@@ -0,0 +1,38 @@
+// retry.rs
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::config;
+
+// Exponential backoff with jitter
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    // attempts in total, the first one included
+    pub max_attempts: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(opts: &config::OptsCommon) -> Self {
+        RetryPolicy {
+            max_attempts: opts.db_retry_max.max(1),
+            base: Duration::from_millis(opts.db_retry_base_ms),
+            max: Duration::from_millis(opts.db_retry_max_ms),
+        }
+    }
+
+    // Delay before retry number n (1, 2, ...): base * 2^(n-1), capped at max,
+    // of which a random half is taken off so that clients do not retry in sync
+    pub fn backoff(&self, n: u32) -> Duration {
+        let exp = self
+            .base
+            .saturating_mul(2u32.saturating_pow(n.saturating_sub(1)))
+            .min(self.max);
+        let half = exp / 2;
+        half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+// EOF
@@ -0,0 +1,325 @@
+// senml.rs
+
+// SenML (RFC 8428) packs in JSON and CBOR form
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use ciborium::Value;
+use serde::{Deserialize, Serialize};
+
+use super::payload::{cbor_error, PayloadError, SensorReadings};
+use super::sensordata::TEMPERATURE;
+
+// Times below 2**28 are relative to the current time
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+// CBOR labels of the fields, RFC 8428 section 6
+const CBOR_LABELS: [(i64, &str); 15] = [
+    (-1, "bver"),
+    (-2, "bn"),
+    (-3, "bt"),
+    (-4, "bu"),
+    (-5, "bv"),
+    (-6, "bs"),
+    (0, "n"),
+    (1, "u"),
+    (2, "v"),
+    (3, "vs"),
+    (4, "vb"),
+    (5, "s"),
+    (6, "t"),
+    (7, "ut"),
+    (8, "vd"),
+];
+
+// Default units of the quantities we know about
+const UNITS: [(&str, &str); 5] = [
+    (TEMPERATURE, "Cel"),
+    ("humidity", "%RH"),
+    ("pressure", "hPa"),
+    ("battery", "V"),
+    ("rssi", "dBm"),
+];
+
+// One SenML record, fields we do not use are ignored
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bt: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bu: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bv: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub u: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub t: Option<f64>,
+}
+
+fn unit_of(quantity: &str) -> Option<&'static str> {
+    UNITS.iter().find(|(q, _)| *q == quantity).map(|(_, u)| *u)
+}
+
+fn quantity_of(unit: Option<&str>) -> Option<&'static str> {
+    unit.and_then(|unit| UNITS.iter().find(|(_, u)| *u == unit))
+        .map(|(q, _)| *q)
+}
+
+pub fn parse_json(payload: &[u8]) -> Result<Vec<Record>, PayloadError> {
+    serde_json::from_slice::<Vec<Record>>(payload)
+        .map_err(|e| PayloadError::new(format!("invalid senml: {e}"), None))
+}
+
+pub fn parse_cbor(payload: &[u8]) -> Result<Vec<Record>, PayloadError> {
+    let value = ciborium::from_reader::<Value, _>(payload)
+        .map_err(|e| PayloadError::new(format!("invalid cbor: {e}"), None))?;
+    let Value::Array(items) = value else {
+        return Err(PayloadError::new("invalid senml: not an array", None));
+    };
+
+    let mut pack = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        let Value::Map(entries) = item else {
+            return Err(PayloadError::new("invalid senml: not a map", Some(i)));
+        };
+        let entries = entries
+            .into_iter()
+            .filter_map(|(k, v)| Some((label_to_name(k)?, v)))
+            .collect();
+        let record = Value::Map(entries)
+            .deserialized()
+            .map_err(|e| PayloadError::new(cbor_error(e), Some(i)))?;
+        pack.push(record);
+    }
+    Ok(pack)
+}
+
+pub fn to_cbor(pack: &[Record]) -> Result<Vec<u8>, String> {
+    let mut items = Vec::with_capacity(pack.len());
+    for record in pack {
+        match Value::serialized(record).map_err(|e| e.to_string())? {
+            Value::Map(entries) => items.push(Value::Map(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (name_to_label(k), v))
+                    .collect(),
+            )),
+            _ => return Err("record is not a map".into()),
+        }
+    }
+    let mut payload = Vec::new();
+    ciborium::into_writer(&Value::Array(items), &mut payload).map_err(|e| e.to_string())?;
+    Ok(payload)
+}
+
+// Unknown integer labels, e.g. of extensions, are dropped like unknown names
+fn label_to_name(key: Value) -> Option<Value> {
+    let Value::Integer(i) = key else {
+        return Some(key);
+    };
+    let label = i64::try_from(i).ok()?;
+    CBOR_LABELS
+        .iter()
+        .find(|(l, _)| *l == label)
+        .map(|(_, name)| Value::Text(name.to_string()))
+}
+
+fn name_to_label(key: Value) -> Value {
+    if let Value::Text(s) = &key
+        && let Some((label, _)) = CBOR_LABELS.iter().find(|(_, n)| n == s)
+    {
+        return Value::Integer((*label).into());
+    }
+    key
+}
+
+// Resolve a pack into readings. The record name is "<id>/<quantity>",
+// or just "<id>" in which case the unit decides the quantity.
+// Records without a numeric value are skipped.
+pub fn readings(pack: Vec<Record>) -> Result<Vec<SensorReadings>, PayloadError> {
+    let now = Utc::now().timestamp() as f64;
+    let mut bn = String::new();
+    let mut bt = 0.0;
+    let mut bu = None;
+    let mut bv = 0.0;
+
+    let mut readings = Vec::with_capacity(pack.len());
+    for (i, record) in pack.into_iter().enumerate() {
+        if let Some(b) = record.bn {
+            bn = b;
+        }
+        if let Some(b) = record.bt {
+            bt = b;
+        }
+        if record.bu.is_some() {
+            bu = record.bu;
+        }
+        if let Some(b) = record.bv {
+            bv = b;
+        }
+        let Some(v) = record.v else {
+            continue;
+        };
+
+        let name = format!("{bn}{}", record.n.unwrap_or_default());
+        let unit = record.u.or_else(|| bu.clone());
+        let (id, quantity) = match name.rsplit_once('/') {
+            Some((id, q)) if !q.is_empty() => (id.to_string(), q.to_string()),
+            // other units would end up calibrated and filtered as temperature
+            _ => match quantity_of(unit.as_deref()) {
+                Some(q) => (name.trim_end_matches('/').to_string(), q.to_string()),
+                None => {
+                    return Err(PayloadError::new(
+                        format!("unknown unit {}", unit.as_deref().unwrap_or("(none)")),
+                        Some(i),
+                    ));
+                }
+            },
+        };
+        let time = bt + record.t.unwrap_or(0.0);
+        let ts = match time {
+            0.0 => None,
+            t if t < RELATIVE_TIME_LIMIT => Some((now + t).round() as i64),
+            t => Some(t.round() as i64),
+        };
+
+        let reading = SensorReadings {
+            id,
+            t: None,
+            ts,
+            values: BTreeMap::from([(quantity, (bv + v) as f32)]),
+            index: Some(i),
+        };
+        reading
+            .validate()
+            .map_err(|e| PayloadError::new(e, Some(i)))?;
+        readings.push(reading);
+    }
+    if readings.is_empty() {
+        return Err(PayloadError::new("no data", None));
+    }
+    Ok(readings)
+}
+
+// Build a pack of (sensor id, quantity, value) measured now
+pub fn pack(values: &[(String, String, f64)]) -> Vec<Record> {
+    let mut pack = values
+        .iter()
+        .map(|(id, quantity, value)| Record {
+            n: Some(format!("{id}/{quantity}")),
+            u: unit_of(quantity).map(String::from),
+            v: Some(*value),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    if let Some(first) = pack.first_mut() {
+        first.bt = Some(Utc::now().timestamp() as f64);
+    }
+    pack
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(n: &str, u: Option<&str>) -> Record {
+        Record {
+            n: Some(n.into()),
+            u: u.map(String::from),
+            v: Some(1.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn quantity_from_unit_or_name() {
+        let readings = readings(vec![
+            record("abc", Some("Cel")),
+            record("abc", Some("%RH")),
+            record("abc/light", Some("lx")),
+        ])
+        .unwrap();
+        let quantities = readings
+            .iter()
+            .map(|r| r.values.keys().next().unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(quantities, vec![TEMPERATURE, "humidity", "light"]);
+    }
+
+    #[test]
+    fn unknown_unit_rejected() {
+        let e = readings(vec![record("abc", Some("Cel")), record("abc", Some("lx"))]).unwrap_err();
+        assert_eq!(e, PayloadError::new("unknown unit lx", Some(1)));
+        let e = readings(vec![record("abc", None)]).unwrap_err();
+        assert_eq!(e, PayloadError::new("unknown unit (none)", Some(0)));
+    }
+
+    fn cbor(records: Vec<Vec<(Value, Value)>>) -> Vec<u8> {
+        let pack = Value::Array(records.into_iter().map(Value::Map).collect());
+        let mut payload = Vec::new();
+        ciborium::into_writer(&pack, &mut payload).unwrap();
+        payload
+    }
+
+    #[test]
+    fn cbor_labels_not_used_ignored() {
+        let label = |l: i64| Value::Integer(l.into());
+        let payload = cbor(vec![
+            vec![
+                (label(-1), label(10)),
+                (label(-2), Value::Text("abc/".into())),
+                (label(-6), Value::Float(0.5)),
+                (label(0), Value::Text(TEMPERATURE.into())),
+                (label(2), Value::Float(21.5)),
+                (label(5), Value::Float(3.0)),
+                (label(7), Value::Float(60.0)),
+                (label(99), Value::Bool(true)),
+                (Value::Text("ext".into()), Value::Null),
+            ],
+            vec![
+                (label(0), Value::Text("state".into())),
+                (label(3), Value::Text("on".into())),
+            ],
+            vec![
+                (label(0), Value::Text("door".into())),
+                (label(4), Value::Bool(true)),
+                (label(8), Value::Text("AQI=".into())),
+            ],
+        ]);
+        let pack = parse_cbor(&payload).unwrap();
+        assert_eq!(
+            pack[0],
+            Record {
+                bn: Some("abc/".into()),
+                n: Some(TEMPERATURE.into()),
+                v: Some(21.5),
+                ..Default::default()
+            }
+        );
+        // records without a numeric value are skipped
+        let readings = readings(pack).unwrap();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].id, "abc");
+        assert_eq!(readings[0].values[TEMPERATURE], 21.5);
+    }
+
+    #[test]
+    fn cbor_error_message() {
+        let payload = cbor(vec![vec![(
+            Value::Integer(2.into()),
+            Value::Text("x".into()),
+        )]]);
+        assert_eq!(
+            parse_cbor(&payload).unwrap_err(),
+            PayloadError::new("invalid type: string \"x\", expected f64", Some(0))
+        );
+    }
+}
+// EOF
@@ -1,34 +1,91 @@
 // sensordata.rs
 
-use std::{collections::HashMap, time};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{self, Duration, SystemTime},
+};
 
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use tracing::*;
 
+use super::calibration::{Calibration, CalibrationTable};
 use super::config;
-use super::tbuf::{Tbuf, Tdata};
+use super::filter::{Reject, SampleFilter};
+use super::payload::SensorReadings;
+use super::sensormeta::{MetaRegistry, SensorMeta};
+use super::tbuf::{AvgMode, Sample, Tbuf, TbufDump, Tdata, WindowStats};
 
 // Note:
 // avgs_t[0] is used for returning the outside temp average
 // avgs_t[1] is used for the average temp to be sent to db
 
-type SensorData = HashMap<String, Tbuf>;
+// Calibration and filtering only apply to temperature readings
+pub const TEMPERATURE: &str = "temperature";
+
+// Tbufs of one sensor, keyed by quantity name
+type Quantities = HashMap<String, Tbuf>;
+type SensorData = HashMap<String, Quantities>;
+
+// State of one sensor quantity for monitoring
+#[derive(Clone, Debug)]
+pub struct SensorStatus {
+    pub sensor_id: String,
+    pub quantity: String,
+    // over the database averaging window
+    pub stats: WindowStats,
+    pub last_seen: Option<SystemTime>,
+    pub stale: bool,
+}
 
 #[derive(Default)]
 pub struct MyData {
     sensor_data: RwLock<SensorData>,
     out_sensor: RwLock<String>,
-    averages_t: RwLock<Vec<u64>>,
+    averages_t: RwLock<Vec<(u64, AvgMode)>>,
+    // used for windows other than the precomputed ones
+    average_mode: AvgMode,
+    ewma_half_life: u64,
+    // accepted distance of client timestamps from now, in seconds
+    ts_max_future: u64,
+    ts_max_past: Option<u64>,
+    // seconds of silence after which a sensor quantity is stale or removed,
+    // 0 disables
+    stale_after: u64,
+    remove_after: u64,
+    filter: RwLock<SampleFilter>,
+    calibration: RwLock<CalibrationTable>,
+    meta: RwLock<MetaRegistry>,
+    // bumped whenever new samples have been stored
+    changed: watch::Sender<u64>,
 }
 
 #[allow(dead_code)]
 impl MyData {
-    pub fn new(opts: &config::OptsCommon) -> Self {
-        MyData {
+    pub fn new(opts: &config::OptsCommon) -> anyhow::Result<Self> {
+        Ok(MyData {
             sensor_data: RwLock::new(SensorData::with_capacity(8)),
             out_sensor: RwLock::new(opts.out_sensor.clone()),
-            averages_t: RwLock::new(vec![opts.average_out_t, opts.average_db_t]),
-        }
+            averages_t: RwLock::new(vec![
+                (
+                    opts.average_out_t,
+                    opts.average_out_mode.unwrap_or(opts.average_mode),
+                ),
+                (
+                    opts.average_db_t,
+                    opts.average_db_mode.unwrap_or(opts.average_mode),
+                ),
+            ]),
+            average_mode: opts.average_mode,
+            ewma_half_life: opts.ewma_half_life,
+            ts_max_future: opts.ts_max_future,
+            ts_max_past: opts.ts_max_past,
+            stale_after: opts.stale_after,
+            remove_after: opts.remove_after,
+            filter: RwLock::new(SampleFilter::new(opts)),
+            calibration: RwLock::new(CalibrationTable::new(opts)?),
+            meta: RwLock::new(MetaRegistry::new(opts)?),
+            changed: watch::Sender::new(0),
+        })
     }
 
     pub async fn expire(&self, interval: u64) {
@@ -37,90 +94,447 @@ impl MyData {
             tokio::time::sleep(wait_duration).await;
             trace!("sensordata_expire active");
 
-            for (sensorid, tbuf) in self.sensor_data.write().await.iter_mut() {
-                let n_expired = tbuf.expire();
-                if n_expired > 0 {
-                    tbuf.update_averages();
-                    info!(
-                        "****** Sensor {sensorid} expired {n_expired} point{}, {} left.",
-                        if n_expired > 1 { "s" } else { "" },
-                        tbuf.len()
-                    );
+            // same lock order as in add_batch()
+            let mut filter = self.filter.write().await;
+            let mut sensor_data = self.sensor_data.write().await;
+            if self.remove_after > 0 {
+                let now = time::SystemTime::now();
+                for (sensorid, quantities) in sensor_data.iter_mut() {
+                    quantities.retain(|quantity, tbuf| {
+                        let dead = silent_for(tbuf, now, self.remove_after);
+                        if dead {
+                            info!("****** Sensor {sensorid} {quantity} is dead, removed.");
+                        }
+                        !dead
+                    });
+                }
+                sensor_data.retain(|sensorid, quantities| {
+                    if quantities.is_empty() {
+                        filter.forget(sensorid);
+                    }
+                    !quantities.is_empty()
+                });
+            }
+
+            for (sensorid, quantities) in sensor_data.iter_mut() {
+                for (quantity, tbuf) in quantities.iter_mut() {
+                    let n_expired = tbuf.expire();
+                    if n_expired > 0 {
+                        tbuf.update_averages();
+                        info!(
+                            "****** Sensor {sensorid} {quantity} expired {n_expired} point{}, {} left.",
+                            if n_expired > 1 { "s" } else { "" },
+                            tbuf.len()
+                        );
+                    }
                 }
             }
         }
     }
 
-    pub async fn add<S: AsRef<str>>(&self, sensor_id: S, temp: f32) {
-        let mut sensor_data = self.sensor_data.write().await;
+    // Add all quantities of the readings, returning one result per reading.
+    // A reading fails if any of its quantities was rejected.
+    pub async fn add_readings(&self, readings: &[SensorReadings]) -> Vec<Result<(), Reject>> {
+        let samples = readings
+            .iter()
+            .enumerate()
+            .flat_map(|(i, r)| r.readings().into_iter().map(move |(q, v)| (i, q, v)))
+            .collect::<Vec<_>>();
+        let results = self
+            .add_batch(samples.iter().map(|(i, q, v)| {
+                let tdata = match readings[*i].ts {
+                    // negative timestamps end up at the epoch and get rejected
+                    Some(ts) => Tdata::new((
+                        *v,
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(ts.max(0) as u64),
+                    )),
+                    None => Tdata::new(*v),
+                };
+                (readings[*i].id.as_str(), q.as_str(), tdata)
+            }))
+            .await;
 
-        if !sensor_data.contains_key(sensor_id.as_ref()) {
-            sensor_data.insert(
-                sensor_id.as_ref().into(),
-                Tbuf::new(&self.averages_t.read().await),
-            );
+        let mut status = vec![Ok(()); readings.len()];
+        for ((i, _, _), res) in samples.iter().zip(results) {
+            if status[*i].is_ok() {
+                status[*i] = res;
+            }
         }
-        match sensor_data.get_mut(sensor_id.as_ref()) {
-            Some(tbuf) => {
-                tbuf.add(Tdata::new(temp));
+        status
+    }
+
+    // Add samples under a single write lock, returning the result of each
+    pub async fn add_batch<'a, I>(&self, samples: I) -> Vec<Result<(), Reject>>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str, Tdata)>,
+    {
+        let calibration = self.calibration.read().await;
+        let mut filter = self.filter.write().await;
+        let averages_t = self.averages_t.read().await;
+        let mut sensor_data = self.sensor_data.write().await;
+
+        // by default accept anything that would still be in the buffer
+        let now = SystemTime::now();
+        let max_past = self
+            .ts_max_past
+            .unwrap_or_else(|| averages_t.iter().map(|(t, _)| *t).max().unwrap_or(0));
+        let too_new = now + Duration::from_secs(self.ts_max_future);
+        let too_old = now
+            .checked_sub(Duration::from_secs(max_past))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        let mut results = Vec::new();
+        for (sensor_id, quantity, mut tdata) in samples {
+            let ts_check = if tdata.ts() > too_new {
+                Err(Reject::Future)
+            } else if tdata.ts() < too_old {
+                Err(Reject::Past)
+            } else if !tdata.data().is_finite() {
+                // inf and NaN would poison the running sums of the windows
+                Err(Reject::NotFinite)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = ts_check {
+                info!("****** Sensor {sensor_id} {quantity} rejected: {e}");
+                results.push(Err(e));
+                continue;
+            }
+
+            if quantity == TEMPERATURE {
+                let cal = calibration.get(sensor_id);
+                tdata = tdata.calibrated(cal.scale, cal.offset);
+
+                if let Err(e) = filter.check(sensor_id, &tdata) {
+                    info!(
+                        "****** Sensor {sensor_id} rejected {:.2}: {e}, {} rejected so far.",
+                        tdata.data(),
+                        filter.rejected(sensor_id)
+                    );
+                    results.push(Err(e));
+                    continue;
+                }
             }
-            None => {
-                error!("What? Tbuf is gone.");
+
+            let quantities = sensor_data.entry(sensor_id.into()).or_default();
+            if !quantities.contains_key(quantity) {
+                quantities.insert(quantity.into(), Tbuf::new(&averages_t, self.ewma_half_life));
             }
+            match quantities.get_mut(quantity) {
+                Some(tbuf) => {
+                    tbuf.add(tdata);
+                }
+                None => {
+                    error!("What? Tbuf is gone.");
+                }
+            }
+            results.push(Ok(()));
+        }
+        if results.iter().any(|res| res.is_ok()) {
+            self.changed.send_modify(|n| *n = n.wrapping_add(1));
         }
+        results
+    }
+
+    // Receiver of the change counter, its changed() returns once new samples
+    // have been stored since the last time it was seen
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changed.subscribe()
     }
 
     pub async fn average_out_t(&self) -> u64 {
-        self.averages_t.read().await[0]
+        self.averages_t.read().await[0].0
     }
 
     pub async fn average_db_t(&self) -> u64 {
-        self.averages_t.read().await[1]
+        self.averages_t.read().await[1].0
+    }
+
+    pub async fn average_out_mode(&self) -> AvgMode {
+        self.averages_t.read().await[0].1
+    }
+
+    pub async fn average_db_mode(&self) -> AvgMode {
+        self.averages_t.read().await[1].1
+    }
+
+    pub fn average_mode(&self) -> AvgMode {
+        self.average_mode
     }
 
-    pub async fn average_get<S: AsRef<str>>(&self, sensor_id: S, t: u64) -> Option<f64> {
+    // Longest averaging window that can be queried, in seconds
+    pub async fn retention(&self) -> u64 {
+        self.averages_t
+            .read()
+            .await
+            .iter()
+            .map(|(t, _)| *t)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub async fn average_get<S: AsRef<str>, Q: AsRef<str>>(
+        &self,
+        sensor_id: S,
+        quantity: Q,
+        t: u64,
+        mode: AvgMode,
+    ) -> Option<f64> {
         let sensor_data = self.sensor_data.read().await;
-        match sensor_data.get(sensor_id.as_ref()) {
+        match sensor_data
+            .get(sensor_id.as_ref())
+            .and_then(|q| q.get(quantity.as_ref()))
+        {
             None => None,
-            Some(d) => d.average(t),
+            Some(d) => d.average(t, mode),
         }
     }
 
+    pub async fn stats_get<S: AsRef<str>, Q: AsRef<str>>(
+        &self,
+        sensor_id: S,
+        quantity: Q,
+        t: u64,
+        mode: AvgMode,
+    ) -> Option<WindowStats> {
+        let sensor_data = self.sensor_data.read().await;
+        match sensor_data
+            .get(sensor_id.as_ref())
+            .and_then(|q| q.get(quantity.as_ref()))
+        {
+            None => None,
+            Some(d) => d.stats(t, mode),
+        }
+    }
+
+    // Buffered samples not older than since (Unix seconds), at most the limit
+    // latest ones, in time order
+    pub async fn history<S: AsRef<str>, Q: AsRef<str>>(
+        &self,
+        sensor_id: S,
+        quantity: Q,
+        since: Option<f64>,
+        limit: Option<usize>,
+    ) -> Option<Vec<Sample>> {
+        let sensor_data = self.sensor_data.read().await;
+        let tbuf = sensor_data
+            .get(sensor_id.as_ref())
+            .and_then(|q| q.get(quantity.as_ref()))?;
+        let mut samples = tbuf
+            .samples()
+            .rev()
+            .take_while(|s| since.is_none_or(|since| s.ts >= since))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect::<Vec<_>>();
+        samples.reverse();
+        Some(samples)
+    }
+
     // out_sensor may have a comma-separated list of sensor ids
     pub async fn average_out(&self) -> Option<f64> {
+        self.average_out_sensor().await.map(|(_, f)| f)
+    }
+
+    // Like average_out(), also returning the id of the sensor used
+    pub async fn average_out_sensor(&self) -> Option<(String, f64)> {
         let out_sensor = self.out_sensor.read().await.clone();
         let out_t = self.average_out_t().await;
+        let out_mode = self.average_out_mode().await;
         for s in out_sensor.split(',') {
-            if let Some(f) = self.average_get(s, out_t).await {
-                return Some(f);
+            if self.is_stale(s, TEMPERATURE).await == Some(false)
+                && let Some(f) = self.average_get(s, TEMPERATURE, out_t, out_mode).await
+            {
+                return Some((s.to_string(), f));
             }
         }
         None
     }
 
+    // Return (sensor id, quantity, average) of all sensors over the given window
+    pub async fn averages_get(&self, t: u64, mode: AvgMode) -> Vec<(String, String, f64)> {
+        let now = SystemTime::now();
+        let mut averages = Vec::new();
+        for (k, quantities) in self.sensor_data.read().await.iter() {
+            for (q, v) in quantities.iter().filter(|(_q, v)| !self.stale(v, now)) {
+                if let Some(avg) = v.average(t, mode) {
+                    averages.push((k.clone(), q.clone(), avg));
+                }
+            }
+        }
+        averages
+    }
+
+    // Whether the sensor quantity is stale, None if there is no such thing
+    pub async fn is_stale<S: AsRef<str>, Q: AsRef<str>>(
+        &self,
+        sensor_id: S,
+        quantity: Q,
+    ) -> Option<bool> {
+        self.sensor_data
+            .read()
+            .await
+            .get(sensor_id.as_ref())
+            .and_then(|q| q.get(quantity.as_ref()))
+            .map(|tbuf| self.stale(tbuf, SystemTime::now()))
+    }
+
+    // Stale data is kept but left out of the averages and database writes
+    fn stale(&self, tbuf: &Tbuf, now: SystemTime) -> bool {
+        self.stale_after > 0 && silent_for(tbuf, now, self.stale_after)
+    }
+
+    // Drop a sensor with all its quantities
+    pub async fn remove_sensor<S: AsRef<str>>(&self, sensor_id: S) -> bool {
+        let removed = self
+            .sensor_data
+            .write()
+            .await
+            .remove(sensor_id.as_ref())
+            .is_some();
+        if removed {
+            self.filter.write().await.forget(sensor_id.as_ref());
+            info!("****** Sensor {} removed.", sensor_id.as_ref());
+        }
+        removed
+    }
+
+    // Move the data of a sensor to a new id, merging it with the data
+    // already there. Returns false if there is no such sensor.
+    pub async fn rename_sensor<S: AsRef<str>, T: AsRef<str>>(&self, from: S, to: T) -> bool {
+        let mut filter = self.filter.write().await;
+        let mut sensor_data = self.sensor_data.write().await;
+        let Some(quantities) = sensor_data.remove(from.as_ref()) else {
+            return false;
+        };
+        let target = sensor_data.entry(to.as_ref().into()).or_default();
+        for (quantity, tbuf) in quantities {
+            match target.get_mut(&quantity) {
+                Some(t) => {
+                    t.merge(tbuf);
+                }
+                None => {
+                    target.insert(quantity, tbuf);
+                }
+            }
+        }
+        filter.forget(from.as_ref());
+        info!(
+            "****** Sensor {} renamed to {}.",
+            from.as_ref(),
+            to.as_ref()
+        );
+        true
+    }
+
     // Return Vec of Strings listing all the sensor ids we have
     pub async fn sensors_list(&self) -> Vec<String> {
         self.sensor_data.read().await.keys().cloned().collect()
     }
 
-    pub async fn averages_db(&self) -> Vec<(String, f64)> {
-        let avg_t_db = self.average_db_t().await;
+    // Return the quantities a sensor has reported
+    pub async fn quantities_list<S: AsRef<str>>(&self, sensor_id: S) -> Option<Vec<String>> {
         self.sensor_data
             .read()
             .await
-            .iter()
-            .filter(|(_k, v)| !v.is_empty())
-            .map(|(k, v)| (k.clone(), v.average(avg_t_db).unwrap_or(0.0)))
-            .collect()
+            .get(sensor_id.as_ref())
+            .map(|q| q.keys().cloned().collect())
+    }
+
+    // Return (sensor id, quantity, stats) of all non-empty Tbufs
+    pub async fn stats_db(&self) -> Vec<(String, String, WindowStats)> {
+        let avg_t_db = self.average_db_t().await;
+        let avg_mode_db = self.average_db_mode().await;
+        let now = SystemTime::now();
+        let mut stats = Vec::new();
+        for (k, quantities) in self.sensor_data.read().await.iter() {
+            for (q, v) in quantities
+                .iter()
+                .filter(|(_q, v)| !v.is_empty() && !self.stale(v, now))
+            {
+                stats.push((
+                    k.clone(),
+                    q.clone(),
+                    v.stats(avg_t_db, avg_mode_db).unwrap_or_default(),
+                ));
+            }
+        }
+        stats
+    }
+
+    // Like stats_db, but stale sensors included and flagged
+    pub async fn status(&self) -> Vec<SensorStatus> {
+        let avg_t_db = self.average_db_t().await;
+        let avg_mode_db = self.average_db_mode().await;
+        let now = SystemTime::now();
+        let mut status = Vec::new();
+        for (k, quantities) in self.sensor_data.read().await.iter() {
+            for (q, v) in quantities.iter().filter(|(_q, v)| !v.is_empty()) {
+                status.push(SensorStatus {
+                    sensor_id: k.clone(),
+                    quantity: q.clone(),
+                    stats: v.stats(avg_t_db, avg_mode_db).unwrap_or_default(),
+                    last_seen: v.last_seen(),
+                    stale: self.stale(v, now),
+                });
+            }
+        }
+        status
     }
 
-    // Just dump our internal sensor data into log
-    pub async fn dump(&self) {
+    // Buffer contents and precomputed averages of all sensors and quantities
+    pub async fn dump(&self) -> BTreeMap<String, BTreeMap<String, TbufDump>> {
         let sensor_data = self.sensor_data.read().await;
         debug!("dump: Have {} sensors.", sensor_data.len());
-        for (sensor_id, tbuf) in sensor_data.iter() {
-            debug!("dump: Sensor {sensor_id} tbuf={tbuf:?}");
-        }
+        sensor_data
+            .iter()
+            .map(|(sensor_id, quantities)| {
+                (
+                    sensor_id.clone(),
+                    quantities
+                        .iter()
+                        .map(|(quantity, tbuf)| (quantity.clone(), tbuf.dump()))
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    // Sorted list of sensor calibrations
+    pub async fn calibrations(&self) -> Vec<(String, Calibration)> {
+        self.calibration.read().await.list()
+    }
+
+    pub async fn set_calibration<S: AsRef<str>>(
+        &self,
+        sensor_id: S,
+        cal: Calibration,
+    ) -> anyhow::Result<()> {
+        self.calibration.write().await.set(sensor_id, cal)
+    }
+
+    pub async fn remove_calibration<S: AsRef<str>>(&self, sensor_id: S) -> anyhow::Result<bool> {
+        self.calibration.write().await.remove(sensor_id)
+    }
+
+    pub async fn meta_get<S: AsRef<str>>(&self, sensor_id: S) -> Option<SensorMeta> {
+        self.meta.read().await.get(sensor_id)
+    }
+
+    // All sensor metadata, keyed by sensor id
+    pub async fn metas(&self) -> BTreeMap<String, SensorMeta> {
+        self.meta.read().await.all()
+    }
+
+    pub async fn set_meta<S: AsRef<str>>(
+        &self,
+        sensor_id: S,
+        meta: SensorMeta,
+    ) -> anyhow::Result<()> {
+        self.meta.write().await.set(sensor_id, meta)
+    }
+
+    pub async fn remove_meta<S: AsRef<str>>(&self, sensor_id: S) -> anyhow::Result<bool> {
+        self.meta.write().await.remove(sensor_id)
     }
 
     // Return our out_sensor id
@@ -133,4 +547,68 @@ impl MyData {
         *s = data.as_ref().to_string();
     }
 }
+
+// Nothing has been heard of the sensor quantity for secs seconds
+fn silent_for(tbuf: &Tbuf, now: SystemTime, secs: u64) -> bool {
+    tbuf.last_seen()
+        .is_none_or(|ts| ts + Duration::from_secs(secs) < now)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn non_finite_rejected() {
+        let mydata = MyData::new(&config::OptsCommon::parse_from(["test"])).unwrap();
+        let results = mydata
+            .add_batch([
+                ("abc", TEMPERATURE, Tdata::new(21.0)),
+                ("abc", TEMPERATURE, Tdata::new(f64::INFINITY)),
+                ("abc", "humidity", Tdata::new(f64::NAN)),
+                ("abc", "humidity", Tdata::new(45.0)),
+            ])
+            .await;
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err(Reject::NotFinite),
+                Err(Reject::NotFinite),
+                Ok(())
+            ]
+        );
+        let stats = mydata
+            .stats_get("abc", TEMPERATURE, 900, AvgMode::Arithmetic)
+            .await
+            .unwrap();
+        assert_eq!((stats.count, stats.mean), (1, 21.0));
+        let stats = mydata
+            .stats_get("abc", "humidity", 900, AvgMode::Arithmetic)
+            .await
+            .unwrap();
+        assert_eq!((stats.count, stats.mean), (1, 45.0));
+    }
+
+    #[tokio::test]
+    async fn changes_not_missed() {
+        let mydata = MyData::new(&config::OptsCommon::parse_from(["test"])).unwrap();
+        let mut changed = mydata.subscribe();
+        // stored while nobody is waiting
+        mydata
+            .add_batch([("abc", TEMPERATURE, Tdata::new(21.0))])
+            .await;
+        tokio::time::timeout(Duration::from_secs(1), changed.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        // rejected samples are no change
+        mydata
+            .add_batch([("abc", TEMPERATURE, Tdata::new(f64::NAN))])
+            .await;
+        assert!(!changed.has_changed().unwrap());
+    }
+}
 // EOF
@@ -0,0 +1,128 @@
+// sensormeta.rs
+
+use std::{collections::BTreeMap, fs};
+
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use super::config;
+use super::sensordata::TEMPERATURE;
+
+// Human-readable information about a sensor
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SensorMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl SensorMeta {
+    // Tags to attach to the database points of a sensor quantity.
+    // The unit is that of the temperature, the other quantities have their own.
+    pub fn db_tags(&self, quantity: &str) -> Vec<(String, String)> {
+        let mut tags = Vec::with_capacity(3 + self.tags.len());
+        let unit = if quantity == TEMPERATURE {
+            &self.unit
+        } else {
+            &None
+        };
+        for (k, v) in [
+            ("name", &self.name),
+            ("location", &self.location),
+            ("unit", unit),
+        ] {
+            if let Some(v) = v {
+                tags.push((k.to_string(), v.clone()));
+            }
+        }
+        for (k, v) in self.tags.iter() {
+            tags.push((k.clone(), v.clone()));
+        }
+        // "sensor" is our own tag, and empty tags are not allowed
+        tags.retain(|(k, v)| k != "sensor" && !k.is_empty() && !v.is_empty());
+        tags
+    }
+}
+
+// Sensor metadata keyed by sensor id, optionally backed by a JSON file
+#[derive(Debug, Default)]
+pub struct MetaRegistry {
+    file: Option<String>,
+    meta: BTreeMap<String, SensorMeta>,
+}
+
+impl MetaRegistry {
+    pub fn new(opts: &config::OptsCommon) -> anyhow::Result<Self> {
+        let mut reg = MetaRegistry {
+            file: opts.meta_file.clone(),
+            meta: BTreeMap::new(),
+        };
+        if let Some(file) = &opts.meta_file {
+            match fs::read_to_string(file) {
+                Ok(data) => reg.meta = serde_json::from_str(&data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    info!("Metadata file {file} not found, starting empty");
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(reg)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(file) = &self.file {
+            fs::write(file, serde_json::to_string_pretty(&self.meta)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get<S: AsRef<str>>(&self, sensor_id: S) -> Option<SensorMeta> {
+        self.meta.get(sensor_id.as_ref()).cloned()
+    }
+
+    pub fn all(&self) -> BTreeMap<String, SensorMeta> {
+        self.meta.clone()
+    }
+
+    pub fn set<S: AsRef<str>>(&mut self, sensor_id: S, meta: SensorMeta) -> anyhow::Result<()> {
+        self.meta.insert(sensor_id.as_ref().into(), meta);
+        self.save()
+    }
+
+    pub fn remove<S: AsRef<str>>(&mut self, sensor_id: S) -> anyhow::Result<bool> {
+        let found = self.meta.remove(sensor_id.as_ref()).is_some();
+        self.save()?;
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_only_on_temperature() {
+        let meta = SensorMeta {
+            location: Some("garden".into()),
+            unit: Some("°C".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            meta.db_tags(TEMPERATURE),
+            vec![
+                ("location".to_string(), "garden".to_string()),
+                ("unit".to_string(), "°C".to_string())
+            ]
+        );
+        assert_eq!(
+            meta.db_tags("humidity"),
+            vec![("location".to_string(), "garden".to_string())]
+        );
+    }
+}
+// EOF
@@ -0,0 +1,90 @@
+// sink.rs
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::*;
+use tokio::time::{sleep, Duration};
+use tracing::*;
+
+use super::retry::RetryPolicy;
+use super::tbuf::WindowStats;
+use crate::*;
+
+// Statistics of one sensor quantity over the database averaging window
+#[derive(Clone, Debug)]
+pub struct Aggregate {
+    pub sensor_id: String,
+    pub quantity: String,
+    // from the sensor metadata
+    pub tags: Vec<(String, String)>,
+    pub stats: WindowStats,
+}
+
+// Output of the aggregates, written every interval
+#[async_trait]
+pub trait Sink: Send + Sync {
+    fn name(&self) -> &str;
+
+    // seconds between writes
+    fn interval(&self) -> i64;
+
+    async fn write(&self, timestamp: i64, data: &[Aggregate]) -> anyhow::Result<()>;
+}
+
+pub async fn aggregates(mystate: &ServerState) -> Vec<Aggregate> {
+    let metas = mystate.mydata.metas().await;
+    mystate
+        .mydata
+        .stats_db()
+        .await
+        .into_iter()
+        .map(|(sensor_id, quantity, stats)| Aggregate {
+            tags: metas
+                .get(&sensor_id)
+                .map(|meta| meta.db_tags(&quantity))
+                .unwrap_or_default(),
+            sensor_id,
+            quantity,
+            stats,
+        })
+        .collect()
+}
+
+// Every sink runs in a task of its own, so that a failing or hanging one
+// does not hold up the others. A write error is logged and the sink carries
+// on at the next interval, a panic restarts it after a delay.
+pub async fn run_sink(sink: Arc<dyn Sink>, mystate: Arc<ServerState>, retry: RetryPolicy) {
+    let mut restarts = 0;
+    loop {
+        let task = tokio::spawn(sink_loop(sink.clone(), mystate.clone()));
+        if let Err(e) = task.await {
+            error!("Sink {} failed: {e}", sink.name());
+        }
+        restarts += 1;
+        sleep(retry.backoff(restarts)).await;
+        error!("Restarting sink {}...", sink.name());
+    }
+}
+
+async fn sink_loop(sink: Arc<dyn Sink>, mystate: Arc<ServerState>) {
+    let interval = sink.interval().max(1);
+    loop {
+        let waitsec = interval - (Utc::now().timestamp() % interval);
+        // wait until next interval start
+        sleep(Duration::new(waitsec as u64, 0)).await;
+
+        let timestamp = Utc::now().timestamp();
+        // in case we overslept :D
+        let timestamp_i = timestamp - (timestamp % interval);
+
+        let data = aggregates(&mystate).await;
+        if data.is_empty() {
+            continue;
+        }
+        if let Err(e) = sink.write(timestamp_i, &data).await {
+            error!("Sink {} write failed: {e}", sink.name());
+        }
+    }
+}
+// EOF
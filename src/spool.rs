@@ -0,0 +1,149 @@
+// spool.rs
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tracing::*;
+
+use super::config;
+
+// Directory of database write batches that could not be sent, one file per
+// batch. The file names are creation times in nanoseconds, so that sorting
+// them gives the order to replay them in.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+}
+
+impl Spool {
+    pub fn new(opts: &config::OptsCommon) -> anyhow::Result<Option<Self>> {
+        let Some(dir) = &opts.spool_dir else {
+            return Ok(None);
+        };
+        fs::create_dir_all(dir)?;
+        let spool = Spool {
+            dir: dir.into(),
+            max_bytes: opts.spool_max_bytes,
+            max_age: Duration::from_secs(opts.spool_max_age),
+        };
+        let n = spool.batches()?.len();
+        if n > 0 {
+            info!("Spool {dir} has {n} batches to replay");
+        }
+        Ok(Some(spool))
+    }
+
+    // Store a batch, then drop the oldest ones if over the limits
+    pub fn push(&self, batch: &[u8]) -> anyhow::Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let path = self.dir.join(format!("{nanos:020}.lp"));
+        // write and rename, so that a crash cannot leave half a batch behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, batch)?;
+        fs::rename(&tmp, &path)?;
+        self.prune()?;
+        Ok(())
+    }
+
+    // Batches to replay, oldest first. Pruned first, as the batches left over
+    // from a previous run may have got too old meanwhile.
+    pub fn pending(&self) -> anyhow::Result<Vec<PathBuf>> {
+        Ok(self.prune()?.into_iter().map(|(path, _)| path).collect())
+    }
+
+    pub fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        Ok(fs::read(path)?)
+    }
+
+    pub fn remove(&self, path: &Path) -> anyhow::Result<()> {
+        Ok(fs::remove_file(path)?)
+    }
+
+    // Drop the batches over the limits, oldest first, returning the rest
+    fn prune(&self) -> anyhow::Result<Vec<(PathBuf, fs::Metadata)>> {
+        let mut batches = self.batches()?;
+        let too_old = SystemTime::now()
+            .checked_sub(self.max_age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut total = batches.iter().map(|(_, meta)| meta.len()).sum::<u64>();
+
+        let mut dropped = 0;
+        for (path, meta) in batches.iter() {
+            let old = meta.modified().is_ok_and(|t| t < too_old);
+            if !old && total <= self.max_bytes {
+                break;
+            }
+            warn!(
+                "Spool: dropping {} ({} bytes), {}",
+                path.display(),
+                meta.len(),
+                if old { "too old" } else { "spool full" }
+            );
+            total -= meta.len();
+            self.remove(path)?;
+            dropped += 1;
+        }
+        batches.drain(..dropped);
+        Ok(batches)
+    }
+
+    // Spooled batches, oldest first
+    fn batches(&self) -> anyhow::Result<Vec<(PathBuf, fs::Metadata)>> {
+        let mut batches = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "lp") {
+                batches.push((path, entry.metadata()?));
+            }
+        }
+        batches.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn old_batches_not_replayed() {
+        let dir = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let opts = config::OptsCommon::parse_from([
+            "test",
+            "--spool-dir",
+            dir.to_str().unwrap(),
+            "--spool-max-age",
+            "3600",
+        ]);
+        let spool = Spool::new(&opts).unwrap().unwrap();
+        spool.push(b"old 1\n").unwrap();
+        spool.push(b"new 2\n").unwrap();
+
+        // the first batch was left over from a run a day ago
+        let (old, _) = spool.batches().unwrap().remove(0);
+        fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(86_400))
+            .unwrap();
+
+        let pending = spool.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(spool.read(&pending[0]).unwrap(), b"new 2\n");
+        spool.remove(&pending[0]).unwrap();
+        assert!(spool.pending().unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+// EOF
@@ -1,13 +1,17 @@
 // tbuf.rs
 
-use std::time::*;
+use std::{collections::VecDeque, time::*};
 
+use clap::ValueEnum;
+use serde::Serialize;
 use tracing::*;
 
 #[derive(Debug)]
 pub struct Tdata {
     timestamp: SystemTime,
     data: f64,
+    // the value as received, before calibration
+    raw: f64,
 }
 
 #[allow(dead_code)]
@@ -24,6 +28,13 @@ impl Tdata {
     pub fn data(&self) -> f64 {
         self.data
     }
+    pub fn raw(&self) -> f64 {
+        self.raw
+    }
+    pub fn calibrated(mut self, scale: f64, offset: f64) -> Tdata {
+        self.data = self.raw * scale + offset;
+        self
+    }
 }
 
 impl From<f32> for Tdata {
@@ -31,6 +42,7 @@ impl From<f32> for Tdata {
         Tdata {
             timestamp: SystemTime::now(),
             data: d as f64,
+            raw: d as f64,
         }
     }
 }
@@ -40,6 +52,7 @@ impl From<f64> for Tdata {
         Tdata {
             timestamp: SystemTime::now(),
             data: d,
+            raw: d,
         }
     }
 }
@@ -49,6 +62,7 @@ impl From<(f32, SystemTime)> for Tdata {
         Tdata {
             timestamp: t,
             data: d as f64,
+            raw: d as f64,
         }
     }
 }
@@ -58,6 +72,7 @@ impl From<(SystemTime, f32)> for Tdata {
         Tdata {
             timestamp: t,
             data: d as f64,
+            raw: d as f64,
         }
     }
 }
@@ -67,6 +82,7 @@ impl From<(f64, SystemTime)> for Tdata {
         Tdata {
             timestamp: t,
             data: d,
+            raw: d,
         }
     }
 }
@@ -76,60 +92,416 @@ impl From<(SystemTime, f64)> for Tdata {
         Tdata {
             timestamp: t,
             data: d,
+            raw: d,
+        }
+    }
+}
+
+// Serializable copy of a sample, ts in Unix seconds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sample {
+    pub ts: f64,
+    pub value: f64,
+    pub raw: f64,
+}
+
+impl From<&Tdata> for Sample {
+    fn from(tdata: &Tdata) -> Self {
+        Sample {
+            ts: secs_between(UNIX_EPOCH, tdata.timestamp),
+            value: tdata.data,
+            raw: tdata.raw,
+        }
+    }
+}
+
+// How the mean of a window is computed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AvgMode {
+    // every sample weighs the same
+    #[default]
+    Arithmetic,
+    // time-weighted, each value is held until the next sample
+    Hold,
+    // time-weighted, linear interpolation between samples
+    Trapezoid,
+    // exponentially weighted moving average, regardless of the window
+    Ewma,
+}
+
+impl AvgMode {
+    pub fn is_time_weighted(&self) -> bool {
+        matches!(self, AvgMode::Hold | AvgMode::Trapezoid)
+    }
+}
+
+// Integral of the data between two consecutive samples
+fn segment(mode: AvgMode, a: &Tdata, b: &Tdata) -> f64 {
+    let dt = secs_between(a.timestamp, b.timestamp);
+    match mode {
+        AvgMode::Arithmetic | AvgMode::Ewma => 0.0,
+        AvgMode::Hold => a.data * dt,
+        AvgMode::Trapezoid => (a.data + b.data) / 2.0 * dt,
+    }
+}
+
+fn secs_between(from: SystemTime, to: SystemTime) -> f64 {
+    to.duration_since(from).unwrap_or_default().as_secs_f64()
+}
+
+// Statistics computed over one averaging window
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct WindowStats {
+    pub count: u64,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub stddev: f64,
+    pub first: f64,
+    pub last: f64,
+    // EWMA of the whole buffer, the same for every window
+    pub ewma: f64,
+}
+
+impl Default for WindowStats {
+    fn default() -> Self {
+        WindowStats {
+            count: 0,
+            mean: f64::NAN,
+            min: f64::NAN,
+            max: f64::NAN,
+            stddev: f64::NAN,
+            first: f64::NAN,
+            last: f64::NAN,
+            ewma: f64::NAN,
+        }
+    }
+}
+
+impl WindowStats {
+    // Compute the statistics of age ordered data
+    pub fn from_data<'a, I>(data: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Tdata>,
+    {
+        let mut count = 0u64;
+        let mut sum = 0.0f64;
+        let mut sum_sq = 0.0f64;
+        let mut min = f64::INFINITY;
+        let mut max = f64::NEG_INFINITY;
+        let mut first = f64::NAN;
+        let mut last = f64::NAN;
+        for tdata in data {
+            if count == 0 {
+                first = tdata.data;
+            }
+            count += 1;
+            sum += tdata.data;
+            sum_sq += tdata.data * tdata.data;
+            min = min.min(tdata.data);
+            max = max.max(tdata.data);
+            last = tdata.data;
+        }
+        WindowStats::from_sums(count, sum, sum_sq, min, max, first, last)
+    }
+
+    fn from_sums(
+        count: u64,
+        sum: f64,
+        sum_sq: f64,
+        min: f64,
+        max: f64,
+        first: f64,
+        last: f64,
+    ) -> Self {
+        if count == 0 {
+            return WindowStats::default();
+        }
+        let n = count as f64;
+        let mean = sum / n;
+        WindowStats {
+            count,
+            mean,
+            min,
+            max,
+            // population stddev, clamped because of rounding errors
+            stddev: (sum_sq / n - mean * mean).max(0.0).sqrt(),
+            first,
+            last,
+            ewma: f64::NAN,
+        }
+    }
+}
+
+// Running state of one precomputed averaging window.
+// Samples are identified by sequence numbers that keep growing
+// as the samples are added and expired, see Tbuf::head_seq.
+#[derive(Debug)]
+struct Window {
+    time_sec: u64,
+    mode: AvgMode,
+    // sequence number of the oldest sample inside the window
+    start: u64,
+    sum: f64,
+    sum_sq: f64,
+    // integral between the samples inside the window, for time-weighted modes
+    integral: f64,
+    // monotonic queues of (seq, data) for sliding min and max
+    min_q: VecDeque<(u64, f64)>,
+    max_q: VecDeque<(u64, f64)>,
+    stats: WindowStats,
+}
+
+impl Window {
+    fn new(time_sec: u64, mode: AvgMode) -> Self {
+        Window {
+            time_sec,
+            mode,
+            start: 0,
+            sum: 0.0,
+            sum_sq: 0.0,
+            integral: 0.0,
+            min_q: VecDeque::new(),
+            max_q: VecDeque::new(),
+            stats: WindowStats::default(),
+        }
+    }
+
+    fn push(&mut self, seq: u64, data: f64) {
+        self.sum += data;
+        self.sum_sq += data * data;
+        while self.min_q.back().is_some_and(|&(_, d)| d >= data) {
+            self.min_q.pop_back();
+        }
+        self.min_q.push_back((seq, data));
+        while self.max_q.back().is_some_and(|&(_, d)| d <= data) {
+            self.max_q.pop_back();
+        }
+        self.max_q.push_back((seq, data));
+    }
+
+    fn pop(&mut self, data: f64) {
+        self.sum -= data;
+        self.sum_sq -= data * data;
+        self.start += 1;
+        while self.min_q.front().is_some_and(|&(seq, _)| seq < self.start) {
+            self.min_q.pop_front();
+        }
+        while self.max_q.front().is_some_and(|&(seq, _)| seq < self.start) {
+            self.max_q.pop_front();
         }
     }
 }
 
+// Precomputed window statistics and samples of a Tbuf
+#[derive(Clone, Debug, Serialize)]
+pub struct TbufDump {
+    pub windows: Vec<WindowDump>,
+    pub ewma: Option<f64>,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WindowDump {
+    pub window: u64,
+    pub mode: AvgMode,
+    pub stats: WindowStats,
+}
+
 #[derive(Debug)]
 pub struct Tbuf {
-    averages_t: Vec<u64>,
-    averages: Vec<f64>,
-    buf: Vec<Tdata>,
+    windows: Vec<Window>,
+    buf: VecDeque<Tdata>,
+    // sequence number of buf[0]
+    head_seq: u64,
     buf_expire: u64,
+    ewma: f64,
+    ewma_half_life: u64,
 }
 
 #[allow(dead_code)]
 impl Tbuf {
-    pub fn new(averages_t: &[u64]) -> Tbuf {
-        Tbuf::with_capacity(64, averages_t)
+    pub fn new(averages_t: &[(u64, AvgMode)], ewma_half_life: u64) -> Tbuf {
+        Tbuf::with_capacity(64, averages_t, ewma_half_life)
     }
 
-    pub fn with_capacity(capacity: usize, averages_t: &[u64]) -> Tbuf {
+    pub fn with_capacity(
+        capacity: usize,
+        averages_t: &[(u64, AvgMode)],
+        ewma_half_life: u64,
+    ) -> Tbuf {
         let mut tbuf = Tbuf {
-            averages_t: averages_t.to_vec(),
-            averages: Vec::with_capacity(averages_t.len()),
-            buf: Vec::with_capacity(capacity),
+            windows: averages_t
+                .iter()
+                .map(|(t, mode)| Window::new(*t, *mode))
+                .collect(),
+            buf: VecDeque::with_capacity(capacity),
+            head_seq: 0,
             buf_expire: 0,
+            ewma: f64::NAN,
+            ewma_half_life,
         };
-        for _a in averages_t {
-            tbuf.averages.push(f64::NAN);
-        }
-        tbuf.buf_expire = *averages_t.iter().max().unwrap_or(&0);
+        tbuf.buf_expire = averages_t.iter().map(|(t, _)| *t).max().unwrap_or(0);
         tbuf.update_averages();
         tbuf
     }
 
     pub fn add(&mut self, data: Tdata) -> &mut Self {
-        self.buf.push(data);
+        if self
+            .buf
+            .back()
+            .is_some_and(|last| data.timestamp < last.timestamp)
+        {
+            return self.insert(data);
+        }
+
+        let seq = self.head_seq + self.buf.len() as u64;
+        for w in self.windows.iter_mut() {
+            if w.start < seq
+                && let Some(prev) = self.buf.back()
+            {
+                w.integral += segment(w.mode, prev, &data);
+            }
+            w.push(seq, data.data);
+        }
+        self.update_ewma(&data);
+        self.buf.push_back(data);
+        self.update_averages();
+        self
+    }
+
+    // Insert a sample older than the latest one in its place and recompute
+    // the windows, O(n). Late samples do not affect the EWMA.
+    fn insert(&mut self, data: Tdata) -> &mut Self {
+        let pos = self
+            .buf
+            .partition_point(|tdata| tdata.timestamp <= data.timestamp);
+        self.buf.insert(pos, data);
+        self.rebuild_windows();
         self.update_averages();
         self
     }
 
+    // Merge the samples of another buffer into this one.
+    // Our EWMA is kept unless we have none.
+    pub fn merge(&mut self, other: Tbuf) -> &mut Self {
+        if self.ewma.is_nan() {
+            self.ewma = other.ewma;
+        }
+        self.buf.extend(other.buf);
+        self.buf
+            .make_contiguous()
+            .sort_by_key(|tdata| tdata.timestamp);
+        self.rebuild_windows();
+        self.update_averages();
+        self
+    }
+
+    fn rebuild_windows(&mut self) {
+        for w in self.windows.iter_mut() {
+            let mut new_w = Window::new(w.time_sec, w.mode);
+            new_w.start = self.head_seq;
+            for (i, tdata) in self.buf.iter().enumerate() {
+                if i > 0 {
+                    new_w.integral += segment(new_w.mode, &self.buf[i - 1], tdata);
+                }
+                new_w.push(self.head_seq + i as u64, tdata.data);
+            }
+            *w = new_w;
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.buf.len()
     }
 
+    // Timestamp of the latest sample
+    pub fn last_seen(&self) -> Option<SystemTime> {
+        self.buf.back().map(|tdata| tdata.timestamp)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
-    pub fn average(&self, time_sec: u64) -> Option<f64> {
-        for i in 0..self.averages_t.len() {
-            if time_sec == self.averages_t[i] {
-                return Some(self.averages[i]);
-            }
+    // Buffer retention, i.e. the longest window we can compute averages over
+    pub fn retention(&self) -> u64 {
+        self.buf_expire
+    }
+
+    // Samples in time order
+    pub fn samples(&self) -> impl DoubleEndedIterator<Item = Sample> + '_ {
+        self.buf.iter().map(Sample::from)
+    }
+
+    pub fn dump(&self) -> TbufDump {
+        TbufDump {
+            windows: self
+                .windows
+                .iter()
+                .map(|w| WindowDump {
+                    window: w.time_sec,
+                    mode: w.mode,
+                    stats: w.stats,
+                })
+                .collect(),
+            ewma: self.ewma(),
+            samples: self.samples().collect(),
+        }
+    }
+
+    // Exponentially weighted moving average, kept even if the buffer is emptied
+    pub fn ewma(&self) -> Option<f64> {
+        if self.ewma.is_nan() {
+            None
+        } else {
+            Some(self.ewma)
+        }
+    }
+
+    // A non-finite value would stick in the average for good, so it is skipped
+    fn update_ewma(&mut self, data: &Tdata) {
+        if !data.data.is_finite() {
+            return;
+        }
+        if self.ewma.is_nan() {
+            self.ewma = data.data;
+            return;
+        }
+        let Some(prev) = self.buf.back() else {
+            return;
+        };
+        // the weight of a new sample grows with the time since the previous one
+        let alpha = if self.ewma_half_life == 0 {
+            1.0
+        } else {
+            let dt = secs_between(prev.timestamp, data.timestamp);
+            1.0 - 0.5f64.powf(dt / self.ewma_half_life as f64)
+        };
+        let ewma = self.ewma + alpha * (data.data - self.ewma);
+        if ewma.is_finite() {
+            self.ewma = ewma;
         }
-        None
+    }
+
+    pub fn average(&self, time_sec: u64, mode: AvgMode) -> Option<f64> {
+        self.stats(time_sec, mode).map(|s| s.mean)
+    }
+
+    pub fn stats(&self, time_sec: u64, mode: AvgMode) -> Option<WindowStats> {
+        // fast path: one of the precomputed windows
+        if let Some(w) = self
+            .windows
+            .iter()
+            .find(|w| w.time_sec == time_sec && w.mode == mode)
+        {
+            return Some(w.stats);
+        }
+        if self.buf.is_empty() || time_sec > self.buf_expire {
+            return None;
+        }
+        Some(self.window_stats(SystemTime::now(), time_sec, mode))
     }
 
     pub fn expire(&mut self) -> usize {
@@ -138,11 +510,16 @@ impl Tbuf {
             .unwrap_or(SystemTime::UNIX_EPOCH);
         let mut n_expired = 0;
 
+        // Expired samples are older than the longest window, so bring
+        // the windows up to date first and they will not contain them.
+        self.update_averages();
+
         // always leave one value
         while self.buf.len() > 1 {
             if self.buf[0].timestamp < too_old {
                 n_expired += 1;
-                let exp_data = self.buf.remove(0);
+                let exp_data = self.buf.pop_front();
+                self.head_seq += 1;
                 trace!("Tbuf expired tdata: {exp_data:?}");
             } else {
                 // The items are age ordered and thus we stop
@@ -154,9 +531,9 @@ impl Tbuf {
         n_expired
     }
 
+    // Slide the precomputed windows up to now and refresh their statistics.
+    // Amortised O(1) per sample, each one enters and leaves a window once.
     pub fn update_averages(&mut self) -> &mut Self {
-        let n_avgs = self.averages_t.len();
-
         if self.buf.is_empty() {
             // do nothing!
             // the old averages will be kept on purpose,
@@ -165,42 +542,333 @@ impl Tbuf {
         }
 
         let now = SystemTime::now();
-        let mut sums = Vec::with_capacity(n_avgs);
-        let mut sizes = Vec::with_capacity(n_avgs);
-        let mut age_threshold = Vec::with_capacity(n_avgs);
-        for i in 0..n_avgs {
-            sums.push(0.0f64);
-            sizes.push(0u64);
-            age_threshold.push(
-                now.checked_sub(Duration::new(self.averages_t[i], 0))
-                    .unwrap_or(SystemTime::UNIX_EPOCH),
-            );
-        }
+        let end_seq = self.head_seq + self.buf.len() as u64;
+        for w in self.windows.iter_mut() {
+            let age_threshold = now
+                .checked_sub(Duration::new(w.time_sec, 0))
+                .unwrap_or(SystemTime::UNIX_EPOCH);
 
-        for buf_i in 0..self.buf.len() {
-            let data = self.buf[buf_i].data;
-            for avg_i in 0..n_avgs {
-                if self.buf[buf_i].timestamp > age_threshold[avg_i] {
-                    sizes[avg_i] += 1;
-                    sums[avg_i] += data;
+            while w.start < end_seq {
+                let i = (w.start - self.head_seq) as usize;
+                let tdata = &self.buf[i];
+                if tdata.timestamp > age_threshold {
+                    break;
+                }
+                if i + 1 < self.buf.len() {
+                    w.integral -= segment(w.mode, tdata, &self.buf[i + 1]);
                 }
+                w.pop(tdata.data);
             }
-        }
 
-        for avg_i in 0..n_avgs {
-            self.averages[avg_i] = match sizes[avg_i] {
-                0 => {
-                    if self.buf.len() == 1 {
-                        // special: if there is only one value, use that and no more questions asked
-                        self.buf[0].data
-                    } else {
-                        f64::NAN
-                    }
+            let count = end_seq - w.start;
+            w.stats = if count == 0 {
+                // reset the running sums to get rid of accumulated rounding errors
+                w.sum = 0.0;
+                w.sum_sq = 0.0;
+                w.integral = 0.0;
+                if self.buf.len() == 1 {
+                    // special: if there is only one value, use that and no more questions asked
+                    WindowStats::from_data(&self.buf)
+                } else {
+                    WindowStats::default()
                 }
-                sz => sums[avg_i] / sz as f64,
+            } else {
+                WindowStats::from_sums(
+                    count,
+                    w.sum,
+                    w.sum_sq,
+                    w.min_q.front().map_or(f64::NAN, |&(_, d)| d),
+                    w.max_q.front().map_or(f64::NAN, |&(_, d)| d),
+                    self.buf[(w.start - self.head_seq) as usize].data,
+                    self.buf[self.buf.len() - 1].data,
+                )
             };
+            if w.mode.is_time_weighted() {
+                let start = (w.start - self.head_seq) as usize;
+                w.stats.mean =
+                    weighted_mean(&self.buf, now, w.time_sec, w.mode, start, Some(w.integral));
+            } else if w.mode == AvgMode::Ewma {
+                w.stats.mean = self.ewma;
+            }
+            w.stats.ewma = self.ewma;
         }
         self
     }
+
+    // Compute the statistics over the last time_sec seconds, counting from now
+    fn window_stats(&self, now: SystemTime, time_sec: u64, mode: AvgMode) -> WindowStats {
+        let age_threshold = now
+            .checked_sub(Duration::new(time_sec, 0))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        // the items are age ordered, find the oldest one inside the window
+        let start = self
+            .buf
+            .partition_point(|tdata| tdata.timestamp <= age_threshold);
+        let mut stats = if start == self.buf.len() && self.buf.len() == 1 {
+            // special: if there is only one value, use that and no more questions asked
+            WindowStats::from_data(&self.buf)
+        } else {
+            WindowStats::from_data(self.buf.range(start..))
+        };
+        if mode.is_time_weighted() {
+            stats.mean = weighted_mean(&self.buf, now, time_sec, mode, start, None);
+        } else if mode == AvgMode::Ewma {
+            stats.mean = self.ewma;
+        }
+        stats.ewma = self.ewma;
+        stats
+    }
+}
+
+// Time-weighted mean over the last time_sec seconds, where buf[start] is the
+// oldest sample inside the window. The integral between the samples inside
+// the window is computed here unless it is already known.
+fn weighted_mean(
+    buf: &VecDeque<Tdata>,
+    now: SystemTime,
+    time_sec: u64,
+    mode: AvgMode,
+    start: usize,
+    inner: Option<f64>,
+) -> f64 {
+    let Some(last) = buf.back() else {
+        return f64::NAN;
+    };
+    if start >= buf.len() {
+        // nothing inside the window, the latest value is held over all of it
+        return last.data;
+    }
+
+    let window_start = now
+        .checked_sub(Duration::new(time_sec, 0))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let first = &buf[start];
+    let inner = inner.unwrap_or_else(|| {
+        buf.range(start..)
+            .zip(buf.range(start + 1..))
+            .map(|(a, b)| segment(mode, a, b))
+            .sum()
+    });
+
+    // the latest value is held until now
+    let mut integral = inner + last.data * secs_between(last.timestamp, now);
+    let mut covered_from = first.timestamp;
+
+    // the sample preceding the window tells what happened at its beginning
+    if start > 0 {
+        let prev = &buf[start - 1];
+        let head = secs_between(window_start, first.timestamp);
+        integral += match mode {
+            AvgMode::Trapezoid => {
+                let span = secs_between(prev.timestamp, first.timestamp);
+                let at_start = if span > 0.0 {
+                    prev.data
+                        + (first.data - prev.data) * secs_between(prev.timestamp, window_start)
+                            / span
+                } else {
+                    first.data
+                };
+                (at_start + first.data) / 2.0 * head
+            }
+            _ => prev.data * head,
+        };
+        covered_from = window_start;
+    }
+
+    let duration = secs_between(covered_from, now);
+    if duration > 0.0 {
+        integral / duration
+    } else {
+        last.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOWS: [(u64, AvgMode); 4] = [
+        (900, AvgMode::Arithmetic),
+        (300, AvgMode::Arithmetic),
+        (600, AvgMode::Hold),
+        (300, AvgMode::Trapezoid),
+    ];
+
+    // Samples k = 0..n, k * 7.5 + 0.5 seconds old, so that none of them
+    // sits on a window boundary while a test runs
+    fn samples(now: SystemTime, n: u64) -> Vec<Tdata> {
+        (0..n)
+            .rev()
+            .map(|k| {
+                let age = Duration::from_millis(k * 7500 + 500);
+                Tdata::new((((k * 37) % 23) as f64 / 3.0, now - age))
+            })
+            .collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        close_to(a, b, 1e-9)
+    }
+
+    fn close_to(a: f64, b: f64, tolerance: f64) -> bool {
+        (a.is_nan() && b.is_nan()) || (a - b).abs() < tolerance
+    }
+
+    // The precomputed windows against computing them from scratch
+    fn check_windows(tbuf: &Tbuf) {
+        check_windows_of(tbuf, &WINDOWS);
+    }
+
+    fn check_windows_of(tbuf: &Tbuf, windows: &[(u64, AvgMode)]) {
+        for &(t, mode) in windows {
+            let fast = tbuf.stats(t, mode).unwrap();
+            let brute = tbuf.window_stats(SystemTime::now(), t, mode);
+            assert_eq!(fast.count, brute.count, "window {t}");
+            // time-weighted means move a little as the clock runs between the two
+            let tolerance = if mode.is_time_weighted() { 1e-3 } else { 1e-9 };
+            assert!(
+                close_to(fast.mean, brute.mean, tolerance),
+                "window {t} {mode:?} mean: {} != {}",
+                fast.mean,
+                brute.mean
+            );
+            for (name, a, b) in [
+                ("min", fast.min, brute.min),
+                ("max", fast.max, brute.max),
+                ("stddev", fast.stddev, brute.stddev),
+                ("first", fast.first, brute.first),
+                ("last", fast.last, brute.last),
+            ] {
+                assert!(close(a, b), "window {t} {name}: {a} != {b}");
+            }
+        }
+    }
+
+    fn is_sorted(tbuf: &Tbuf) -> bool {
+        tbuf.buf
+            .iter()
+            .zip(tbuf.buf.iter().skip(1))
+            .all(|(a, b)| a.timestamp <= b.timestamp)
+    }
+
+    #[test]
+    fn sliding_windows() {
+        let mut tbuf = Tbuf::new(&WINDOWS, 600);
+        for tdata in samples(SystemTime::now(), 200) {
+            tbuf.add(tdata);
+            check_windows(&tbuf);
+        }
+        assert_eq!(tbuf.stats(300, AvgMode::Arithmetic).unwrap().count, 40);
+        assert_eq!(tbuf.stats(900, AvgMode::Arithmetic).unwrap().count, 120);
+    }
+
+    #[test]
+    fn expire() {
+        let mut tbuf = Tbuf::new(&WINDOWS, 600);
+        for tdata in samples(SystemTime::now(), 200) {
+            tbuf.add(tdata);
+        }
+        assert_eq!(tbuf.expire(), 80);
+        assert_eq!(tbuf.len(), 120);
+        check_windows(&tbuf);
+
+        // one value is always left
+        let mut tbuf = Tbuf::new(&WINDOWS, 600);
+        tbuf.add(Tdata::new((
+            21.0,
+            SystemTime::now() - Duration::from_secs(3600),
+        )));
+        assert_eq!(tbuf.expire(), 0);
+        tbuf.update_averages();
+        assert_eq!(tbuf.average(300, AvgMode::Arithmetic), Some(21.0));
+    }
+
+    #[test]
+    fn insert_late() {
+        let mut tbuf = Tbuf::new(&WINDOWS, 600);
+        let mut data = samples(SystemTime::now(), 150);
+        // every third sample arrives late, after all the others
+        let late = (0..data.len())
+            .rev()
+            .filter(|i| i % 3 == 0)
+            .map(|i| data.remove(i))
+            .collect::<Vec<_>>();
+        for tdata in data {
+            tbuf.add(tdata);
+        }
+        for tdata in late {
+            tbuf.add(tdata);
+            assert!(is_sorted(&tbuf));
+            check_windows(&tbuf);
+        }
+        assert_eq!(tbuf.len(), 150);
+
+        // and the windows keep sliding correctly after the rebuild
+        for tdata in samples(SystemTime::now(), 1) {
+            tbuf.add(tdata);
+        }
+        check_windows(&tbuf);
+    }
+
+    // A value held from before the window start, and a step inside it
+    #[test]
+    fn time_weighted_boundary() {
+        let now = SystemTime::now();
+        let mut tbuf = Tbuf::new(&[(300, AvgMode::Hold), (300, AvgMode::Trapezoid)], 600);
+        tbuf.add(Tdata::new((10.0, now - Duration::from_secs(400))));
+        tbuf.add(Tdata::new((20.0, now - Duration::from_secs(100))));
+
+        // 10 over 200 s, then 20 over 100 s
+        let hold = tbuf.average(300, AvgMode::Hold).unwrap();
+        assert!(close_to(hold, 40.0 / 3.0, 1e-3), "hold {hold}");
+        // 10 -> 20 over 400..100 s interpolates to 13.33 at 300 s,
+        // then 20 is held over the last 100 s
+        let at_start = 10.0 + 10.0 / 3.0;
+        let expected = ((at_start + 20.0) / 2.0 * 200.0 + 20.0 * 100.0) / 300.0;
+        let trapezoid = tbuf.average(300, AvgMode::Trapezoid).unwrap();
+        assert!(close_to(trapezoid, expected, 1e-3), "trapezoid {trapezoid}");
+        check_windows_of(&tbuf, &[(300, AvgMode::Hold), (300, AvgMode::Trapezoid)]);
+    }
+
+    #[test]
+    fn ewma_skips_non_finite() {
+        let now = SystemTime::now();
+        let mut tbuf = Tbuf::new(&WINDOWS, 600);
+        tbuf.add(Tdata::new((f64::NAN, now - Duration::from_secs(30))));
+        assert_eq!(tbuf.ewma(), None);
+        tbuf.add(Tdata::new((21.0, now - Duration::from_secs(20))));
+        assert_eq!(tbuf.ewma(), Some(21.0));
+        tbuf.add(Tdata::new((f64::INFINITY, now - Duration::from_secs(10))));
+        assert_eq!(tbuf.ewma(), Some(21.0));
+        tbuf.add(Tdata::new((22.0, now)));
+        assert!(tbuf.ewma().is_some_and(|ewma| ewma > 21.0 && ewma < 22.0));
+    }
+
+    #[test]
+    fn merge() {
+        let now = SystemTime::now();
+        let mut a = Tbuf::new(&WINDOWS, 600);
+        let mut b = Tbuf::new(&WINDOWS, 600);
+        for (i, tdata) in samples(now, 100).into_iter().enumerate() {
+            if i % 2 == 0 {
+                a.add(tdata);
+            } else {
+                b.add(tdata);
+            }
+        }
+        let ewma = a.ewma();
+        a.merge(b);
+        assert_eq!(a.len(), 100);
+        assert!(is_sorted(&a));
+        assert_eq!(a.ewma(), ewma);
+        check_windows(&a);
+
+        // the EWMA of the other buffer is taken if we have none
+        let mut empty = Tbuf::new(&WINDOWS, 600);
+        empty.merge(a);
+        assert_eq!(empty.ewma(), ewma);
+        check_windows(&empty);
+    }
 }
 // EOF